@@ -19,5 +19,8 @@ glob = "0.3.2"
 uuid = { version = "1.12.0", features = ["v4"] }
 clap = { version = "4.5.21", features = ["derive"] }
 bytes = "1.9.0"
+sha2 = "0.10"
+serde_json = "1.0"
+libc = "0.2"
 ''')
 
@@ -20,14 +20,14 @@ f"""// ===========================================
 
 use std::{{collections::HashMap, sync::Arc}};
 use tokio::task::JoinSet;
-use crate::{{orchestra::LocationInfo, swirl::{{PortData, StepArgument, StepOutput, Swirl}}}};
+use crate::{{orchestra::{{utils::ResourceLimits, LocationInfo}}, swirl::{{choice::{{Branch, Guard}}, options::SwirlOptions, FailurePolicy, OutputType, PortData, RetryPolicy, StepArgument, StepOptions, StepOutput, Swirl}}}};
 
-pub async fn {location.name}(location: String, address_map: HashMap<String, LocationInfo>) {{
+pub async fn {location.name}(location: String, address_map: HashMap<String, LocationInfo>, options: SwirlOptions) {{
   println!("Running {{}}", location);
 
   let start = std::time::Instant::now();
 
-  let swirl = Arc::new(Swirl::new(location.clone(), address_map, "/workdir/{location.name}".into()));
+  let swirl = Arc::new(Swirl::new(location.clone(), address_map, "/workdir/{location.name}".into(), options));
   swirl.amdahline.register_executor(&"{location.name}".to_string());
 """)
 
@@ -41,8 +41,20 @@ nodes=($nodes)
 # Clear or create location map file
 > address_map.txt
 
-# clear the workdir
-rm -rf ~/.swirl/workdir/*
+# clear the workdir, unless resuming an interrupted run (SWIRL_RESUME=1) from the journals it holds.
+# The cached step outputs (in the cache directory of each location) are kept across runs
+resume_flag=""
+if [ -n "$SWIRL_RESUME" ]; then
+  resume_flag="--resume"
+else
+  find ~/.swirl/workdir -mindepth 2 -maxdepth 2 ! -name cache -exec rm -rf {{}} +
+fi
+
+# run every step, instead of reusing the cached outputs of a previous run (SWIRL_NO_CACHE=1)
+cache_flag=""
+if [ -n "$SWIRL_NO_CACHE" ]; then
+  cache_flag="--no-cache"
+fi
 
 num_nodes=$SLURM_NNODES
 num_locations=${{#locations[@]}}
@@ -79,7 +91,7 @@ for ((i=0; i < num_locations; i++)); do
     --bind ~/.swirl/workdir:/workdir \\
     --bind ~/data:/data \\
     docker://mul8/1000genome-swirlc \\
-    ./swirlc-rust --loc=$loc &
+    ./swirlc-rust --loc=$loc $resume_flag $cache_flag &
 done
 
 wait
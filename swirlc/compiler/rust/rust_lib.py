@@ -17,7 +17,7 @@ def build_main_file(output_dir, locations: list[Location]):
 
     for location in locations:
         location_spawns += f"""
-\t\t"{location.name}" => join_set.spawn(locations::{location.name}::{location.name}("{location.name}".to_string(), address_map.clone())),"""
+\t\t"{location.name}" => join_set.spawn(locations::{location.name}::{location.name}("{location.name}".to_string(), address_map.clone(), args.options.clone())),"""
 
 
     with open(output_dir + "/src/main.rs", "w") as f:
@@ -29,6 +29,7 @@ pub mod orchestra;
 pub mod amdahline;
 
 use clap::Parser;
+use swirl::options::SwirlOptions;
 use tokio::{{process::Child, task::JoinSet}};
 
 /// Simple program to greet a person
@@ -38,6 +39,9 @@ struct Args {{
     // Location
     #[arg(short, long)]
     loc: String,
+
+    #[command(flatten)]
+    options: SwirlOptions,
 }}
 
 #[tokio::main]
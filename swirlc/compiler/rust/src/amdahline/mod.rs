@@ -27,6 +27,10 @@ impl Amdahline {
     drop(self.writer.write().unwrap());
   }
 
+  pub fn flush(&self) {
+    self.writer.write().unwrap().flush().unwrap();
+  }
+
   pub fn register_executor(&self, executor_id: &String) {
     let write = self.writer.write();
 
@@ -45,6 +49,15 @@ impl Amdahline {
     write.unwrap().write_all(message.as_bytes()).unwrap();
   }
 
+  pub fn annotate_executor(&self, executor_id: &String, key: &str, value: &String) {
+    let write = self.writer.write();
+
+    let time = chrono::Local::now().format("%H:%M:%S:%f").to_string();
+    let message = format!("[{}] ANNOTATE <{}> {}=\"{}\"\n", time, executor_id, key, value);
+
+    write.unwrap().write_all(message.as_bytes()).unwrap();
+  }
+
   pub fn begin_task(&self, executor_id: &String, task: &String) -> uuid::Uuid {
     let write = self.writer.write();
 
@@ -58,6 +71,15 @@ impl Amdahline {
     uuid
   }
 
+  pub fn annotate_task(&self, executor_id: &String, uuid: uuid::Uuid, key: &str, value: &String) {
+    let write = self.writer.write();
+
+    let time = chrono::Local::now().format("%H:%M:%S:%f").to_string();
+    let message = format!("[{}] ANNOTATE <{}> <{}> {}=\"{}\"\n", time, executor_id, uuid, key, value);
+
+    write.unwrap().write_all(message.as_bytes()).unwrap();
+  }
+
   pub fn end_task(&self, executor_id: &String, uuid: uuid::Uuid) {
     let write = self.writer.write();
 
@@ -5,6 +5,7 @@ pub mod orchestra;
 pub mod amdahline;
 
 use clap::Parser;
+use swirl::options::SwirlOptions;
 use tokio::{process::Child, task::JoinSet};
 
 /// Simple program to greet a person
@@ -14,6 +15,9 @@ struct Args {
   // Location
   #[arg(short, long)]
   loc: String,
+
+  #[command(flatten)]
+  options: SwirlOptions,
 }
 
 #[tokio::main]
@@ -21,12 +25,14 @@ async fn main() {
   let address_map = orchestra::utils::addresses_from_config_file("address_map.txt");
   let mut join_set = JoinSet::new();
 
+  let args = Args::parse();
+
   for (location, _) in address_map.clone() {
     println!("{} ", location);
     match location.as_str() {
-      "location0" => join_set.spawn(locations::location0::location0("location0".to_string(), address_map.clone())),
-      "location1" => join_set.spawn(locations::location1::location1("location1".to_string(), address_map.clone())),
-      "location2" => join_set.spawn(locations::location2::location2("location2".to_string(), address_map.clone())),
+      "location0" => join_set.spawn(locations::location0::location0("location0".to_string(), address_map.clone(), args.options.clone())),
+      "location1" => join_set.spawn(locations::location1::location1("location1".to_string(), address_map.clone(), args.options.clone())),
+      "location2" => join_set.spawn(locations::location2::location2("location2".to_string(), address_map.clone(), args.options.clone())),
       _ => panic!("Invalid location: {}", location)
     };
   }
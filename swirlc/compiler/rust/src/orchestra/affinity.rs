@@ -0,0 +1,129 @@
+/**
+ * Returns the cores the calling thread can run on.
+ */
+#[cfg(target_os = "linux")]
+pub fn current_cores() -> Vec<usize> {
+  let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+  let result = unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+  if result != 0 {
+    return vec![];
+  }
+
+  (0..libc::CPU_SETSIZE as usize)
+    .filter(|core| unsafe { libc::CPU_ISSET(*core, &set) })
+    .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_cores() -> Vec<usize> {
+  vec![]
+}
+
+/**
+ * Returns the CPU set holding `cores`.
+ */
+#[cfg(target_os = "linux")]
+pub fn cpu_set(cores: &Vec<usize>) -> libc::cpu_set_t {
+  let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+  for core in cores {
+    unsafe { libc::CPU_SET(*core, &mut set) };
+  }
+
+  set
+}
+
+/**
+ * Pins every thread of this process to `cores`, the processes spawned afterwards inherit the affinity.
+ */
+#[cfg(target_os = "linux")]
+pub fn pin_process(cores: &Vec<usize>) -> Result<(), String> {
+  let set = cpu_set(cores);
+
+  // sched_setaffinity applies to a single thread, so each thread of the runtime is pinned
+  let threads = std::fs::read_dir("/proc/self/task").map_err(|e| e.to_string())?;
+
+  for thread in threads {
+    let thread = thread.map_err(|e| e.to_string())?;
+    let tid: libc::pid_t = match thread.file_name().to_string_lossy().parse() {
+      Ok(tid) => tid,
+      Err(_) => continue,
+    };
+
+    let result = unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if result != 0 {
+      return Err(format!("failed to pin thread {}: {}", tid, std::io::Error::last_os_error()));
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_process(_cores: &Vec<usize>) -> Result<(), String> {
+  Err("CPU affinity is only supported on Linux".to_string())
+}
+
+/**
+ * Returns the share of `cores` of the `index`-th of `count` locations running on the same node.
+ * The shares are disjoint, unless there are more locations than cores.
+ */
+pub fn location_share(cores: &Vec<usize>, index: usize, count: usize) -> Vec<usize> {
+  if cores.is_empty() {
+    return vec![];
+  }
+
+  if cores.len() < count {
+    return vec![cores[index % cores.len()]];
+  }
+
+  // the first cores.len() % count locations get one more core
+  let size = cores.len() / count;
+  let extra = cores.len() % count;
+  let start = index * size + index.min(extra);
+  let end = start + size + if index < extra { 1 } else { 0 };
+
+  cores[start..end].to_vec()
+}
+
+/**
+ * Formats `cores` as a comma separated list, e.g. "0,1,2,3".
+ */
+pub fn format_cores(cores: &Vec<usize>) -> String {
+  cores
+    .iter()
+    .map(|core| core.to_string())
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn locations_get_disjoint_shares() {
+    let cores = (0..10).collect::<Vec<_>>();
+
+    let shares = (0..3).map(|index| location_share(&cores, index, 3)).collect::<Vec<_>>();
+
+    // the first location gets the extra core
+    assert_eq!(shares, vec![vec![0, 1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+  }
+
+  #[test]
+  fn locations_share_cores_when_they_are_more() {
+    let cores = vec![2, 3];
+
+    let shares = (0..3).map(|index| location_share(&cores, index, 3)).collect::<Vec<_>>();
+
+    assert_eq!(shares, vec![vec![2], vec![3], vec![2]]);
+    assert!(location_share(&vec![], 0, 1).is_empty());
+  }
+
+  #[test]
+  fn cores_are_formatted_as_a_list() {
+    assert_eq!(format_cores(&vec![0, 2, 5]), "0,2,5");
+  }
+}
@@ -187,6 +187,7 @@ impl Orchestra {
   * Reads the data in the reader `R` and sends it to the destinations.
   * `header_data` is a byte array that can be used to send additional data with the message header.
    (note that there is a maximum size limit for the header, by default `MESSAGE_HEADER_SIZE` bytes).
+  * Broadcasts are not resumable: if a connection drops, the broadcast panics.
   * `BLOCKING`: `.await` blocks the task until the whole message is sent.
   */
   pub async fn broadcast_blocking<R>(
@@ -349,6 +350,9 @@ impl Orchestra {
         size: data_size,
         relay_tag: tag.clone(),
         header_data,
+        stripe: None,
+        transfer_id: None,
+        content_hash: None,
       };
 
       let mut buffer = bincode::serialize(&message_header).unwrap();
@@ -0,0 +1,163 @@
+use std::{path::PathBuf, sync::OnceLock};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Parent cgroup of the step cgroups, None if no writable cgroup v2 hierarchy is available.
+static STEPS_PARENT: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// StepCgroup is a cgroup v2 holding the processes of a step, removed when dropped.
+#[derive(Debug)]
+pub struct StepCgroup {
+  pub path: PathBuf,
+}
+
+impl StepCgroup {
+  /**
+   * Creates the cgroup `name` limited to `memory` bytes and `cpus` cores.
+   * Returns None if no writable cgroup v2 hierarchy is available.
+   */
+  pub fn create(name: &String, memory: Option<u64>, cpus: Option<u32>) -> Option<StepCgroup> {
+    let parent = STEPS_PARENT.get_or_init(steps_parent).as_ref()?;
+    let path = parent.join(name);
+
+    if let Err(error) = std::fs::create_dir(&path) {
+      if error.kind() != std::io::ErrorKind::AlreadyExists {
+        println!("Failed to create cgroup {:?}: {}", path, error);
+        return None;
+      }
+    }
+
+    let cgroup = StepCgroup { path };
+
+    if let Some(memory) = memory {
+      cgroup.write("memory.max", &memory.to_string());
+      // the step is killed instead of swapping
+      cgroup.write("memory.swap.max", "0");
+    }
+
+    if let Some(cpus) = cpus {
+      let period = 100_000;
+      cgroup.write("cpu.max", &format!("{} {}", cpus as u64 * period, period));
+    }
+
+    Some(cgroup)
+  }
+
+  fn write(&self, file: &str, value: &str) {
+    let path = self.path.join(file);
+
+    // a controller might not be enabled, the limit is then not enforced
+    if path.exists() {
+      if let Err(error) = std::fs::write(&path, value) {
+        println!("Failed to write {:?} to {:?}: {}", value, path, error);
+      }
+    }
+  }
+}
+
+impl Drop for StepCgroup {
+  fn drop(&mut self) {
+    if let Err(error) = std::fs::remove_dir(&self.path) {
+      println!("Failed to remove cgroup {:?}: {}", self.path, error);
+    }
+  }
+}
+
+/**
+ * Returns true if a process of the cgroup at `path` was killed for exceeding its memory limit.
+ */
+pub fn oom_killed(path: &PathBuf) -> bool {
+  let events = std::fs::read_to_string(path.join("memory.events")).unwrap_or_default();
+
+  events
+    .lines()
+    .filter_map(|line| line.strip_prefix("oom_kill "))
+    .any(|count| count.trim().parse::<u64>().unwrap_or(0) > 0)
+}
+
+/**
+ * Returns the directory of the cgroup of this process followed by the ones of its ancestors,
+ * in the v1 hierarchy of `controller`, or in the v2 one if `controller` is None.
+ * A cgroup not visible in the mounted hierarchy (e.g. outside of the cgroup namespace) is left out.
+ */
+pub fn own_cgroups(controller: Option<&str>) -> Vec<PathBuf> {
+  let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+
+  // each line is "<id>:<controllers>:<path>", the v2 hierarchy has id 0 and no controllers
+  let own = cgroups.lines().find_map(|line| {
+    let mut fields = line.splitn(3, ':');
+    let id = fields.next()?;
+    let controllers = fields.next()?;
+    let path = fields.next()?;
+
+    let found = match controller {
+      Some(controller) => controllers.split(',').any(|c| c == controller),
+      None => id == "0" && controllers.is_empty(),
+    };
+    found.then(|| path.to_string())
+  });
+
+  let Some(own) = own else {
+    return vec![];
+  };
+
+  let root = match controller {
+    Some(controller) => PathBuf::from(CGROUP_ROOT).join(controller),
+    None => PathBuf::from(CGROUP_ROOT),
+  };
+
+  let own = root.join(own.trim_start_matches('/'));
+
+  own
+    .ancestors()
+    .take_while(|cgroup| cgroup.starts_with(&root))
+    .filter(|cgroup| cgroup.is_dir())
+    .map(|cgroup| cgroup.to_path_buf())
+    .collect()
+}
+
+/**
+ * Prepares the cgroup of this process to hold the step cgroups and returns its path.
+ * Processes can only live in the leaves of a cgroup v2 hierarchy with controllers,
+ * so this process is moved to a `swirl_location` leaf, sibling of the step cgroups.
+ */
+fn steps_parent() -> Option<PathBuf> {
+  let result = (|| -> Result<PathBuf, String> {
+    // the cgroup v2 of the process is the "0::<path>" line
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").map_err(|e| e.to_string())?;
+    let own = cgroups
+      .lines()
+      .find_map(|line| line.strip_prefix("0::"))
+      .ok_or("no cgroup v2 hierarchy")?;
+
+    let own = PathBuf::from(CGROUP_ROOT).join(own.trim_start_matches('/'));
+    if !own.join("cgroup.controllers").exists() {
+      return Err(format!("{:?} is not a cgroup v2 with controllers", own));
+    }
+
+    let leaf = own.join("swirl_location");
+    if !leaf.exists() {
+      std::fs::create_dir(&leaf).map_err(|e| format!("failed to create {:?}: {}", leaf, e))?;
+    }
+    std::fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+      .map_err(|e| format!("failed to move to {:?}: {}", leaf, e))?;
+
+    let controllers = std::fs::read_to_string(own.join("cgroup.controllers")).unwrap_or_default();
+    for controller in ["memory", "cpu"] {
+      if controllers.split_whitespace().any(|c| c == controller) {
+        std::fs::write(own.join("cgroup.subtree_control"), format!("+{}", controller))
+          .map_err(|e| format!("failed to enable the {} controller: {}", controller, e))?;
+      }
+    }
+
+    Ok(own)
+  })();
+
+  match result {
+    Ok(parent) => Some(parent),
+    Err(error) => {
+      println!("Cgroups are not available, the steps are not limited by cgroups: {}", error);
+      None
+    }
+  }
+}
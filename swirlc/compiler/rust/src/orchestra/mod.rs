@@ -1,19 +1,29 @@
+pub mod affinity;
 pub mod broadcast;
+pub mod cgroup;
 pub mod receive;
 pub mod send;
 pub mod utils;
 
-use std::{collections::HashMap, io::Read, net::{SocketAddr, SocketAddrV4}, str::FromStr, sync::Arc, task, thread};
+use std::{collections::{HashMap, HashSet}, io::Read, net::{SocketAddr, SocketAddrV4}, str::FromStr, sync::Arc, task, thread};
 
 use tokio::{
-  io::{AsyncReadExt, BufReader},
+  io::{AsyncReadExt, AsyncWriteExt, BufReader},
   net::{TcpListener, TcpStream},
-  sync::RwLock,
+  sync::{watch, RwLock},
 };
 use utils::debug_prelude;
 
 const MESSAGE_HEADER_SIZE: usize = 1024 * 2;
 const MESSAGE_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const MAX_RESUME_ATTEMPTS: u32 = 10;
+const RESUME_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+/// Sent by the receiver instead of the persisted bytes when it already stores a file with the announced content hash
+const ALREADY_STORED: u64 = u64::MAX;
+/// Id of the message telling the other locations that the workflow is aborting
+const ABORT_MESSAGE_ID: &str = "#abort";
+/// Time spent trying to tell a location that the workflow is aborting, it might have already exited
+const ABORT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 pub type LocationID = u16;
 
@@ -75,6 +85,29 @@ impl RelayInstruction {
 }
 
 
+/**
+ * Describes the byte range of a file carried by a message when the file is sent over multiple connections.
+ * The stripe with index 0 uses the original message id, the others use `stripe_message_id`.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq, Debug, Clone)]
+pub struct Stripe {
+  pub index: usize,
+  pub count: usize,
+  pub offset: usize,
+  pub total_size: usize,
+}
+
+/**
+ * Returns the message id used by the stripe `index` of the message `message_id`.
+ */
+pub fn stripe_message_id(message_id: &str, index: usize) -> String {
+  if index == 0 {
+    message_id.to_string()
+  } else {
+    format!("{}#stripe{}", message_id, index)
+  }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Hash, Eq, PartialEq, Debug)]
 pub struct MessageHeader {
   pub sender: LocationID,
@@ -83,6 +116,11 @@ pub struct MessageHeader {
   pub header_data: Vec<u8>,
   pub size: usize,
   pub relay_tag: RelayInstruction,
+  pub stripe: Option<Stripe>,
+  /// Set for resumable transfers, identifies the transfer across reconnections.
+  pub transfer_id: Option<String>,
+  /// Content hash of the transferred file, announced so the receiver can skip files it already stores.
+  pub content_hash: Option<String>,
 }
 
 pub struct Orchestra {
@@ -91,6 +129,11 @@ pub struct Orchestra {
   locations: HashMap<String, LocationID>,
   incoming_messages:
     Arc<RwLock<HashMap<(LocationID, String), (MessageHeader, TcpStream)>>>,
+  completed_transfers: Arc<RwLock<HashSet<String>>>,
+  /// incremented every time a message is added to the incoming messages, to wake up the tasks waiting for one
+  arrivals: watch::Sender<u64>,
+  /// set to the location aborting the workflow, when another location tells so
+  aborted: watch::Sender<Option<LocationID>>,
 }
 
 unsafe impl Send for Orchestra {}
@@ -119,6 +162,9 @@ impl Orchestra {
       addresses,
       location,
       incoming_messages: Arc::new(RwLock::new(HashMap::new())),
+      completed_transfers: Arc::new(RwLock::new(HashSet::new())),
+      arrivals: watch::channel(0).0,
+      aborted: watch::channel(None).0,
     }
   }
 
@@ -156,6 +202,11 @@ impl Orchestra {
     self.addresses.get(&location).unwrap().clone()
   }
 
+  /// Returns a receiver of the location aborting the workflow, set when another location aborts it.
+  pub fn aborted(&self) -> watch::Receiver<Option<LocationID>> {
+    self.aborted.subscribe()
+  }
+
   /**
   * Spawns a task accepting incoming connections from other locations in a loop,
    abort the handle to close the listener.
@@ -213,6 +264,22 @@ impl Orchestra {
 
     let message_header: MessageHeader = bincode::deserialize(&buffer).unwrap();
 
+    // another location is aborting the workflow, nothing else will be sent
+    if message_header.message_id == ABORT_MESSAGE_ID {
+      orchestra.aborted.send_replace(Some(message_header.origin));
+      return;
+    }
+
+    // the sender reconnected after the receiver already completed the transfer (the final acknowledgement was lost)
+    if let Some(transfer_id) = &message_header.transfer_id {
+      if orchestra.completed_transfers.read().await.contains(transfer_id) {
+        let size = message_header.size as u64;
+        let _ = stream.write_u64(size).await;
+        let _ = stream.write_u64(size).await;
+        return;
+      }
+    }
+
     // println!(
     //   "{} Received message (tag: {:?}) from {:?} origin {:?}",
     //   debug_prelude(&orchestra.self_name(), None),
@@ -232,5 +299,7 @@ impl Orchestra {
         ),
         (message_header, stream),
       );
+
+    orchestra.arrivals.send_modify(|arrivals| *arrivals += 1);
   }
 }
\ No newline at end of file
@@ -1,10 +1,10 @@
-use std::sync::Arc;
+use std::{io::SeekFrom, path::PathBuf, sync::Arc};
 
-use super::{LocationID, Orchestra, RelayInstruction};
-use crate::orchestra::MessageHeader;
+use super::{stripe_message_id, LocationID, Orchestra, RelayInstruction};
+use crate::orchestra::{utils::debug_prelude, MessageHeader, ALREADY_STORED, MESSAGE_CHUNK_SIZE};
 use bytes::Bytes;
 use tokio::{
-  io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
   net::TcpStream,
   task::{JoinHandle, JoinSet},
 };
@@ -147,6 +147,200 @@ impl PartialReceive {
     join_set
   }
   // ======================================================
+
+  // ==================== Receive File Transfer ==========
+  /**
+   * Collects a file sent with `Orchestra::blocking_send_striped` into `path`.
+   * The message is the first stripe, the remaining stripes are received from the same origin
+    and written at their offset in parallel.
+   * The data is written to a temporary `.part` file, renamed to `path` once every stripe is complete.
+   * Files are content-addressed in the `store` directory: if the sender announces the hash of a file
+    already in the store, the data is skipped and the stored file is copied to `path`.
+   * The store keeps its own copies, so that the steps modifying their inputs in place do not corrupt it.
+   * `BLOCKING`: `.await` blocks the task until the whole file is written.
+   */
+  pub async fn collect_blocking_file_transfer(self, path: PathBuf, store: PathBuf) {
+    let stripe = self.header.stripe.clone().expect("file transfers must carry a stripe");
+    let content_hash = self.header.content_hash.clone();
+
+    if let Some(content_hash) = &content_hash {
+      let stored_path = store.join(content_hash);
+
+      if stored_path.exists() {
+        self.collect_blocking_already_stored(stripe.count).await;
+
+        copy_file(&stored_path, &path).await;
+        return;
+      }
+    }
+
+    let mut part_path = path.clone().into_os_string();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+
+    let file = tokio::fs::File::create(&part_path)
+      .await
+      .expect(format!("failed to create file: {:?}", &part_path).as_str());
+    file
+      .set_len(stripe.total_size as u64)
+      .await
+      .expect("failed to allocate file");
+    drop(file);
+
+    let orchestra = self.orchestra.clone();
+    let origin = self.header.origin;
+    let message_id = self.header.message_id.clone();
+
+    let mut join_set = JoinSet::new();
+
+    join_set.spawn(self.collect_blocking_resumable(part_path.clone()));
+
+    for index in 1..stripe.count {
+      let orchestra = orchestra.clone();
+      let message_id = stripe_message_id(&message_id, index);
+      let part_path = part_path.clone();
+
+      join_set.spawn(async move {
+        let received = orchestra.receive_blocking(origin, message_id).await;
+        received.collect_blocking_resumable(part_path).await;
+      });
+    }
+
+    join_set.join_all().await;
+
+    tokio::fs::rename(&part_path, &path)
+      .await
+      .expect(format!("failed to rename {:?} to {:?}", &part_path, &path).as_str());
+
+    if let Some(content_hash) = content_hash {
+      std::fs::create_dir_all(&store)
+        .expect(format!("failed to create directory {:?}", &store).as_str());
+
+      let stored_path = store.join(&content_hash);
+
+      // the copy is renamed into the store once complete, another transfer may have stored the same content in the meantime
+      if !stored_path.exists() {
+        let part_path = store.join(format!("{}.{}.part", content_hash, uuid::Uuid::new_v4()));
+
+        copy_file(&path, &part_path).await;
+        tokio::fs::rename(&part_path, &stored_path)
+          .await
+          .expect(format!("failed to rename {:?} to {:?}", &part_path, &stored_path).as_str());
+      }
+    }
+  }
+
+  /**
+   * Answers every stripe of the transfer that the file is already stored, so that the sender skips the data.
+   */
+  async fn collect_blocking_already_stored(self, stripes: usize) {
+    let orchestra = self.orchestra.clone();
+    let origin = self.header.origin;
+    let message_id = self.header.message_id.clone();
+
+    let mut received = self;
+
+    for index in 0..stripes {
+      if index > 0 {
+        received = orchestra.receive_blocking(origin, stripe_message_id(&message_id, index)).await;
+      }
+
+      // the sender resumes with a new connection (answered by `handle_connection`) if this reply is lost
+      let _ = received.stream.write_u64(ALREADY_STORED).await;
+      let _ = received.stream.flush().await;
+
+      if let Some(transfer_id) = &received.header.transfer_id {
+        orchestra.completed_transfers.write().await.insert(transfer_id.clone());
+      }
+    }
+  }
+
+  /**
+   * Writes the data of a single stripe at its offset in the (already allocated) file at `path`.
+   * If the connection drops, waits for the sender to reconnect and resumes from the bytes already persisted.
+   */
+  async fn collect_blocking_resumable(self, path: PathBuf) {
+    let orchestra = self.orchestra.clone();
+    let origin = self.header.origin;
+    let message_id = self.header.message_id.clone();
+    let transfer_id = self.header.transfer_id.clone();
+
+    let mut received = self;
+    let mut persisted = 0;
+
+    loop {
+      match received.try_collect_resumable(&path, &mut persisted).await {
+        Ok(()) => break,
+        Err(error) => {
+          println!(
+            "{} Connection from {:?} lost while receiving {:?} ({:?}), waiting for the sender to resume",
+            debug_prelude(&orchestra.self_name(), None),
+            origin,
+            path,
+            error
+          );
+
+          received = orchestra.receive_blocking(origin, message_id.clone()).await;
+        }
+      }
+    }
+
+    if let Some(transfer_id) = transfer_id {
+      orchestra.completed_transfers.write().await.insert(transfer_id);
+    }
+  }
+
+  /**
+   * Performs a single attempt of a resumable transfer (see `Orchestra::try_send_file_range`),
+    `persisted` is updated with the number of bytes written to the file.
+   */
+  async fn try_collect_resumable(&mut self, path: &PathBuf, persisted: &mut usize) -> std::io::Result<()> {
+    let offset = self.header.stripe.as_ref().map_or(0, |stripe| stripe.offset);
+    let size = self.header.size;
+
+    self.stream.write_u64(*persisted as u64).await?;
+    self.stream.flush().await?;
+
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.seek(SeekFrom::Start((offset + *persisted) as u64)).await?;
+
+    let mut buffer = vec![0; MESSAGE_CHUNK_SIZE.min(size.max(1))];
+
+    while *persisted < size {
+      let remaining = (size - *persisted).min(buffer.len());
+      let read = self.stream.read(&mut buffer[..remaining]).await?;
+
+      if read == 0 {
+        return Err(std::io::Error::new(
+          std::io::ErrorKind::UnexpectedEof,
+          format!("received {} of {} bytes", persisted, size),
+        ));
+      }
+
+      file.write_all(&buffer[..read]).await?;
+      file.flush().await?;
+
+      *persisted += read;
+    }
+
+    self.stream.write_u64(*persisted as u64).await?;
+    self.stream.flush().await?;
+
+    Ok(())
+  }
+  // ======================================================
+}
+
+/**
+ * Copies `source` to `destination`, replacing it.
+ * Files are not hard linked: a step modifying the linked file in place would modify both.
+ */
+async fn copy_file(source: &PathBuf, destination: &PathBuf) {
+  let _ = tokio::fs::remove_file(destination).await;
+
+  tokio::fs::copy(source, destination)
+    .await
+    .expect(format!("failed to copy {:?} to {:?}", source, destination).as_str());
 }
 
 impl Orchestra {
@@ -159,25 +353,42 @@ impl Orchestra {
     sender: LocationID,
     message_id: String,
   ) -> (MessageHeader, TcpStream) {
+    let key = (sender, message_id);
+
+    // subscribed before checking, so that a message added in between is not missed
+    let mut arrivals = self.arrivals.subscribe();
+
     loop {
-      let incoming_messages = self.incoming_messages.read().await;
+      if let Some(message) = self.incoming_messages.write().await.remove(&key) {
+        return message;
+      }
 
-      if incoming_messages.contains_key(&(sender, message_id.clone())) {
-        drop(incoming_messages);
+      arrivals.changed().await.expect("failed to wait for messages");
+    }
+  }
 
-        let (header, stream) = self
-          .incoming_messages
-          .write()
-          .await
-          .remove(&(sender, message_id))
-          .unwrap();
+  /**
+   * Waits for the first of `messages` (sender and message id) to be in the incoming messages buffer,
+   * leaving it there to be received, and returns its index.
+   * `BLOCKING`: `.await` blocks the task until one of the messages is available.
+   */
+  pub async fn wait_for_first_message(
+    self: &Arc<Self>,
+    messages: &Vec<(LocationID, String)>,
+  ) -> usize {
+    // subscribed before checking, so that a message added in between is not missed
+    let mut arrivals = self.arrivals.subscribe();
+
+    loop {
+      let incoming_messages = self.incoming_messages.read().await;
 
-        return (header, stream);
+      if let Some(index) = messages.iter().position(|message| incoming_messages.contains_key(message)) {
+        return index;
       }
 
       drop(incoming_messages);
-      
-      tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+
+      arrivals.changed().await.expect("failed to wait for messages");
     }
   }
 
@@ -230,3 +441,122 @@ impl Orchestra {
     join_set
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::orchestra::LocationInfo;
+
+  /// Returns the orchestras of two locations, listening on free local ports.
+  fn orchestras() -> (Arc<Orchestra>, Arc<Orchestra>) {
+    let mut address_map = HashMap::new();
+
+    for location in ["first", "second"] {
+      let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+      let address = listener.local_addr().expect("failed to get address").to_string();
+
+      address_map.insert(location.to_string(), LocationInfo { address, machine: "localhost".to_string() });
+    }
+
+    let first = Arc::new(Orchestra::new("first".to_string(), address_map.clone()));
+    let second = Arc::new(Orchestra::new("second".to_string(), address_map));
+    second.accept_connections();
+
+    (first, second)
+  }
+
+  #[tokio::test]
+  async fn first_message_to_arrive_is_chosen() {
+    let (sender, receiver) = orchestras();
+    let messages = vec![(sender.location, "a".to_string()), (sender.location, "b".to_string())];
+
+    let waiting = tokio::spawn({
+      let receiver = receiver.clone();
+      async move { receiver.wait_for_first_message(&messages).await }
+    });
+
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+
+    sender.blocking_send(receiver.location, "b".to_string(), &b"data"[..], Bytes::new(), 4, sender.location).await;
+    assert_eq!(waiting.await.unwrap(), 1);
+
+    // the message is left to be received by the branch taken
+    let received = receiver.receive_blocking(sender.location, "b".to_string()).await;
+    assert_eq!(received.collect_blocking_vecu8().await, b"data".to_vec());
+  }
+
+  #[tokio::test]
+  async fn message_already_arrived_is_chosen() {
+    let (sender, receiver) = orchestras();
+
+    sender.blocking_send(receiver.location, "b".to_string(), &b"b"[..], Bytes::new(), 1, sender.location).await;
+    sender.blocking_send(receiver.location, "a".to_string(), &b"a"[..], Bytes::new(), 1, sender.location).await;
+
+    let received = receiver.receive_blocking(sender.location, "a".to_string()).await;
+    assert_eq!(received.collect_blocking_vecu8().await, b"a".to_vec());
+
+    let messages = vec![(sender.location, "a".to_string()), (sender.location, "b".to_string())];
+    assert_eq!(receiver.wait_for_first_message(&messages).await, 1);
+  }
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("swirl_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir
+  }
+
+  /// Sends the file at `source` in `stripes` and receives it into `destination`, storing its content under `store`.
+  async fn transfer(source: &PathBuf, destination: &PathBuf, store: &PathBuf, stripes: usize, content_hash: Option<String>) {
+    let (sender, receiver) = orchestras();
+    let size = std::fs::metadata(source).unwrap().len() as usize;
+
+    let receiving = tokio::spawn({
+      let (receiver, destination, store) = (receiver.clone(), destination.clone(), store.clone());
+      let origin = sender.location;
+      async move {
+        let received = receiver.receive_blocking(origin, "file".to_string()).await;
+        received.collect_blocking_file_transfer(destination, store).await;
+      }
+    });
+
+    sender.blocking_send_striped(receiver.location, "file".to_string(), source.clone(), Bytes::new(), size, stripes, content_hash, sender.location).await;
+    receiving.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn file_is_reassembled_from_stripes() {
+    let dir = temp_dir();
+    let source = dir.join("source.bin");
+    let content = (0..1_000_003u32).map(|byte| (byte % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(&source, &content).unwrap();
+
+    for stripes in [1, 4, 7] {
+      let destination = dir.join(format!("received_{}.bin", stripes));
+      transfer(&source, &destination, &dir.join("store"), stripes, None).await;
+
+      assert_eq!(std::fs::read(&destination).unwrap(), content);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn stored_content_is_not_sent_again() {
+    let dir = temp_dir();
+    let store = dir.join("store");
+    std::fs::write(dir.join("first.txt"), "content").unwrap();
+    std::fs::write(dir.join("second.txt"), "other content").unwrap();
+
+    transfer(&dir.join("first.txt"), &dir.join("received_first.txt"), &store, 2, Some("hash".to_string())).await;
+    assert_eq!(std::fs::read_to_string(store.join("hash")).unwrap(), "content");
+
+    // the announced hash is already stored: the data is skipped and the stored copy is used
+    transfer(&dir.join("second.txt"), &dir.join("received_second.txt"), &store, 2, Some("hash".to_string())).await;
+    assert_eq!(std::fs::read_to_string(dir.join("received_second.txt")).unwrap(), "content");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
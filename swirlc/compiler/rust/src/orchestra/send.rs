@@ -1,16 +1,17 @@
-use crate::orchestra::{utils::{debug_prelude, format_bytes}, MessageHeader, RelayInstruction, MESSAGE_HEADER_SIZE};
-use super::{LocationID, Orchestra};
+use crate::orchestra::{utils::{debug_prelude, format_bytes}, MessageHeader, RelayInstruction, ABORT_CONNECT_TIMEOUT, ABORT_MESSAGE_ID, ALREADY_STORED, MAX_RESUME_ATTEMPTS, MESSAGE_HEADER_SIZE, RESUME_DELAY};
+use super::{stripe_message_id, LocationID, Orchestra, Stripe};
 
-use std::{sync::Arc, vec};
+use std::{io::SeekFrom, path::PathBuf, sync::Arc, vec};
 
 use bytes::Bytes;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter}, net::TcpStream, task::{JoinHandle, JoinSet}};
+use tokio::{io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter}, net::TcpStream, task::{JoinHandle, JoinSet}};
 
 impl Orchestra {
   /**
    * Reads the data in the reader `R` and sends it to the destination.
    * `header_data` is a byte array that can be used to send additional data with the message header.
     (note that there is a maximum size limit for the header, by default `MESSAGE_HEADER_SIZE` bytes).
+   * The message is not resumable (e.g. a streamed archive), use `blocking_send_striped` to send files.
    * `BLOCKING`: `.await` blocks the task until the whole message is sent.
    */
   pub async fn blocking_send<R>(
@@ -24,25 +25,28 @@ impl Orchestra {
   )
     where R: AsyncReadExt + Unpin + Send + 'static
   {
-    let location_info = self.addresses.get(&destination).expect(format!("<Orchestra> unknown destination: {:?}", &destination).as_str());
-
-    let mut stream;
+    self.blocking_send_message(destination, message_id, reader, header_data, data_size, origin, None).await;
+  }
 
-    let start = std::time::Instant::now();
-    while {
-      stream = TcpStream::connect(&location_info.address).await;
-      stream.is_err()
-    } {
-      tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-    }
-    // println!(
-    //   "{} connected to {:?} in {:?}",
-    //   debug_prelude(&self.location, None),
-    //   &destination,
-    //   start.elapsed()
-    // );
-
-    let stream = stream.unwrap();
+  /**
+   * **NOTE**: Support function, use `blocking_send` or `blocking_send_striped` instead.
+   * Connects to the destination and writes the message header followed by the data in the reader `R`.
+   * The message is not resumable: if the connection drops, the send panics.
+   * `BLOCKING`: `.await` blocks the task until the whole message is sent.
+   */
+  async fn blocking_send_message<R>(
+    self: &Arc<Self>,
+    destination: LocationID,
+    message_id: String,
+    reader: R,
+    header_data: Bytes,
+    data_size: usize,
+    origin: LocationID,
+    stripe: Option<Stripe>,
+  )
+    where R: AsyncReadExt + Unpin + Send + 'static
+  {
+    let stream = self.connect(destination).await;
     let mut writer = BufWriter::with_capacity(1024*1024*64, stream);
     // let mut writer = BufWriter::new(stream);
 
@@ -53,7 +57,10 @@ impl Orchestra {
       message_id,
       size: data_size,
       relay_tag: RelayInstruction::End,
-      header_data: header_data.to_vec()
+      header_data: header_data.to_vec(),
+      stripe,
+      transfer_id: None,
+      content_hash: None,
     };
 
     let mut buffer = bincode::serialize(&message_header).unwrap();
@@ -81,6 +88,285 @@ impl Orchestra {
     drop(reader);
   }
 
+  /**
+   * **NOTE**: Support function, used before the first message to a destination.
+   * Connects to the destination, waiting for it to accept connections (e.g. while the location is starting).
+   */
+  async fn connect(self: &Arc<Self>, destination: LocationID) -> TcpStream {
+    let location_info = self.addresses.get(&destination).expect(format!("<Orchestra> unknown destination: {:?}", &destination).as_str());
+
+    loop {
+      match TcpStream::connect(&location_info.address).await {
+        Ok(stream) => return stream,
+        Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(10)).await,
+      }
+    }
+  }
+
+  /**
+   * Tells the other locations that the workflow is aborting, so that they stop waiting for data that will never be sent.
+   * The locations that cannot be reached within `ABORT_CONNECT_TIMEOUT` (e.g. already exited) are skipped.
+   * `BLOCKING`: `.await` blocks the task until all the locations are told or skipped.
+   */
+  pub async fn abort_locations(self: &Arc<Self>) {
+    let message_header = MessageHeader {
+      sender: self.location,
+      origin: self.location,
+      message_id: ABORT_MESSAGE_ID.to_string(),
+      size: 0,
+      relay_tag: RelayInstruction::End,
+      header_data: vec![],
+      stripe: None,
+      transfer_id: None,
+      content_hash: None,
+    };
+
+    let mut buffer = bincode::serialize(&message_header).unwrap();
+    buffer.resize(MESSAGE_HEADER_SIZE, 0);
+
+    let mut join_set = JoinSet::new();
+
+    for destination in self.location_ids() {
+      if destination == self.location {
+        continue;
+      }
+
+      let orchestra = self.clone();
+      let buffer = buffer.clone();
+
+      join_set.spawn(async move {
+        let stream = tokio::time::timeout(ABORT_CONNECT_TIMEOUT, orchestra.connect(destination)).await;
+
+        if let Ok(mut stream) = stream {
+          let _ = stream.write_all(&buffer).await;
+          let _ = stream.flush().await;
+        }
+      });
+    }
+
+    join_set.join_all().await;
+  }
+
+  /**
+   * Sends the file at `path` to the destination, splitting it into `stripes` byte ranges sent over parallel connections.
+   * The receiver reassembles the file with `PartialReceive::collect_blocking_file_transfer`.
+   * `header_data` is attached to every stripe.
+   * When `content_hash` is given, the receiver may answer that it already stores the file, skipping the data.
+   * Each stripe is resumable: if the connection drops, the sender reconnects and resumes from the bytes
+    the receiver already persisted (see `blocking_send_file_range`).
+   * `BLOCKING`: `.await` blocks the task until all the stripes are sent.
+   */
+  pub async fn blocking_send_striped(
+    self: &Arc<Self>,
+    destination: LocationID,
+    message_id: String,
+    path: PathBuf,
+    header_data: Bytes,
+    file_size: usize,
+    stripes: usize,
+    content_hash: Option<String>,
+    origin: LocationID
+  ) {
+    let stripes = stripes.max(1).min(file_size.max(1));
+    let stripe_size = file_size.div_ceil(stripes);
+
+    let mut join_set = JoinSet::new();
+
+    for index in 0..stripes {
+      let orchestra = self.clone();
+      let path = path.clone();
+      let header_data = header_data.clone();
+      let content_hash = content_hash.clone();
+
+      let offset = index * stripe_size;
+      let length = stripe_size.min(file_size.saturating_sub(offset));
+
+      let stripe = Stripe {
+        index,
+        count: stripes,
+        offset,
+        total_size: file_size,
+      };
+
+      let message_id = stripe_message_id(&message_id, index);
+
+      join_set.spawn(async move {
+        orchestra.blocking_send_file_range(destination, message_id, path, header_data, stripe, length, content_hash, origin).await;
+      });
+    }
+
+    join_set.join_all().await;
+  }
+
+  /**
+   * **NOTE**: Support function, use `blocking_send_striped` instead.
+   * Sends `length` bytes of the file at `path`, starting at the offset of the stripe.
+   * When the connection drops the transfer is retried (up to `MAX_RESUME_ATTEMPTS` times, counting the failed
+    reconnections), resuming from the number of bytes the receiver reports as persisted.
+   * `BLOCKING`: `.await` blocks the task until the receiver acknowledges the whole range.
+   */
+  async fn blocking_send_file_range(
+    self: &Arc<Self>,
+    destination: LocationID,
+    message_id: String,
+    path: PathBuf,
+    header_data: Bytes,
+    stripe: Stripe,
+    length: usize,
+    content_hash: Option<String>,
+    origin: LocationID,
+  ) {
+    // identifies this transfer across reconnections
+    let transfer_id = uuid::Uuid::new_v4().to_string();
+
+    let mut attempt = 0;
+
+    loop {
+      // the first attempt waits for the destination to start, the reconnections count as attempts
+      let stream = match attempt {
+        0 => Ok(self.connect(destination).await),
+        _ => TcpStream::connect(&self.addresses[&destination].address).await,
+      };
+
+      let result = match stream {
+        Ok(stream) => self.try_send_file_range(
+          stream,
+          destination,
+          message_id.clone(),
+          &path,
+          header_data.clone(),
+          stripe.clone(),
+          length,
+          content_hash.clone(),
+          origin,
+          transfer_id.clone(),
+        ).await,
+        Err(error) => Err(error),
+      };
+
+      match result {
+        Ok(()) => return,
+        Err(error) => {
+          attempt += 1;
+
+          if attempt > MAX_RESUME_ATTEMPTS {
+            panic!(
+              "{} PANIC: failed to send {:?} to {:?} after {} attempts: {:?}",
+              debug_prelude(&self.self_name(), None),
+              path,
+              destination,
+              attempt,
+              error
+            );
+          }
+
+          println!(
+            "{} Connection to {:?} lost while sending {:?} ({:?}), resuming (attempt {})",
+            debug_prelude(&self.self_name(), None),
+            destination,
+            path,
+            error,
+            attempt
+          );
+
+          tokio::time::sleep(RESUME_DELAY * attempt).await;
+        }
+      }
+    }
+  }
+
+  /**
+   * **NOTE**: Support function, use `blocking_send_striped` instead.
+   * Performs a single attempt of a resumable transfer over `stream`:
+   * 1. writes the message header,
+   * 2. reads the number of bytes already persisted by the receiver (or `ALREADY_STORED`),
+   * 3. sends the remaining bytes of the range,
+   * 4. reads the final acknowledgement of the receiver.
+   */
+  async fn try_send_file_range(
+    self: &Arc<Self>,
+    mut stream: TcpStream,
+    destination: LocationID,
+    message_id: String,
+    path: &PathBuf,
+    header_data: Bytes,
+    stripe: Stripe,
+    length: usize,
+    content_hash: Option<String>,
+    origin: LocationID,
+    transfer_id: String,
+  ) -> std::io::Result<()> {
+    // === Write message header ===
+    let offset = stripe.offset;
+    let index = stripe.index;
+
+    let message_header = MessageHeader {
+      sender: self.location.clone(),
+      origin,
+      message_id,
+      size: length,
+      relay_tag: RelayInstruction::End,
+      header_data: header_data.to_vec(),
+      stripe: Some(stripe),
+      transfer_id: Some(transfer_id),
+      content_hash,
+    };
+
+    let mut buffer = bincode::serialize(&message_header).unwrap();
+
+    assert!(
+      buffer.len() <= MESSAGE_HEADER_SIZE,
+      "{} PANIC: message too large: {:?}",
+      debug_prelude(&self.location, None),
+      buffer.len()
+    );
+    buffer.resize(MESSAGE_HEADER_SIZE, 0);
+
+    stream.write_all(&buffer).await?;
+    stream.flush().await?;
+
+    // === Resume from the bytes persisted by the receiver ===
+    let persisted = stream.read_u64().await?;
+
+    if persisted == ALREADY_STORED {
+      if index == 0 {
+        println!(
+          "{} {:?} already stores {:?}, skipping the data",
+          debug_prelude(&self.self_name(), None),
+          destination,
+          path
+        );
+      }
+
+      return Ok(());
+    }
+
+    let persisted = persisted as usize;
+
+    if persisted < length {
+      let mut file = tokio::fs::File::open(path).await?;
+      file.seek(SeekFrom::Start((offset + persisted) as u64)).await?;
+
+      let mut reader = BufReader::with_capacity(1024*1024*64, file.take((length - persisted) as u64));
+      let mut writer = BufWriter::with_capacity(1024*1024*64, &mut stream);
+
+      tokio::io::copy(&mut reader, &mut writer).await?;
+      writer.flush().await?;
+    }
+
+    // === Wait for the receiver to acknowledge the whole range ===
+    let acknowledged = stream.read_u64().await? as usize;
+
+    if acknowledged != length {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        format!("receiver acknowledged {} of {} bytes", acknowledged, length),
+      ));
+    }
+
+    Ok(())
+  }
+
   /**
    * Reads the data in the reader `R` and sends it to the destination.
    * `header_data` is a byte array that can be used to send additional data with the message header.
@@ -1,6 +1,6 @@
-use std::{collections::HashMap, path::PathBuf, process::{ExitStatus, Output, Stdio}};
+use std::{collections::HashMap, path::PathBuf, process::{ExitStatus, Stdio}, time::Duration};
 
-use tokio::process::Child;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, process::Child, sync::watch};
 
 use super::LocationInfo;
 
@@ -35,6 +35,96 @@ pub fn debug_prelude<Loc>(location: &Loc, step_name: Option<&String>) -> String
   format!("[{}] [{}]{} >>> ", time, location, step_name)
 }
 
+/**
+ * Computes the SHA-256 digest of the file at `path`, as a lowercase hex string.
+ */
+pub async fn file_content_hash(path: PathBuf) -> String {
+  tokio::task::spawn_blocking(move || {
+    use sha2::{Digest, Sha256};
+
+    let mut file = std::fs::File::open(&path)
+      .expect(format!("failed to open file: {:?}", path).as_str());
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).expect("failed to hash file");
+
+    format!("{:x}", hasher.finalize())
+  })
+  .await
+  .expect("failed to join hashing task")
+}
+
+/**
+ * Returns the total size of the regular files in the directory at `path` (recursively).
+ */
+pub fn directory_size(path: &PathBuf) -> usize {
+  let mut size = 0;
+
+  for entry in std::fs::read_dir(path).expect(format!("failed to read dir: {:?}", path).as_str()) {
+    let entry = entry.expect("failed to read dir entry");
+    let metadata = entry.metadata().expect("failed to read metadata");
+
+    if metadata.is_dir() {
+      size += directory_size(&entry.path());
+    } else {
+      size += metadata.len() as usize;
+    }
+  }
+
+  size
+}
+
+/**
+ * Returns the total size of the files (or directories) at `paths`.
+ */
+pub fn paths_size(paths: &Vec<PathBuf>) -> usize {
+  paths
+    .iter()
+    .map(|path| {
+      if path.is_dir() {
+        directory_size(path)
+      } else {
+        std::fs::metadata(path).map_or(0, |metadata| metadata.len() as usize)
+      }
+    })
+    .sum()
+}
+
+/**
+ * Spawns a `tar` process writing an archive of the files (or directories) at `paths` to its standard output.
+ * Each path is stored as a top-level entry named after its last component.
+ */
+pub fn archive(paths: &Vec<PathBuf>) -> Child {
+  let mut command = tokio::process::Command::new("tar");
+  command.arg("-cf").arg("-");
+
+  for path in paths {
+    let parent = path.parent().expect("failed to get parent directory");
+    let name = path.file_name().expect("failed to get file name");
+
+    command.arg("-C").arg(parent).arg(name);
+  }
+
+  command
+    .stdout(Stdio::piped())
+    .spawn()
+    .expect(format!("failed to archive: {:?}", paths).as_str())
+}
+
+/**
+ * Spawns a `tar` process extracting the archive read from its standard input into the directory at `path`.
+ */
+pub fn extract_archive(path: &PathBuf) -> Child {
+  tokio::process::Command::new("tar")
+    .arg("-xf")
+    .arg("-")
+    .arg("-C")
+    .arg(path)
+    .stdin(Stdio::piped())
+    .spawn()
+    .expect(format!("failed to extract archive into: {:?}", path).as_str())
+}
+
 pub fn addresses_from_config_file(file_path: &str) -> HashMap<String, LocationInfo> {
   let mut location_map = HashMap::new();
 
@@ -54,68 +144,478 @@ pub fn addresses_from_config_file(file_path: &str) -> HashMap<String, LocationIn
   location_map
 }
 
-pub async fn execute_command_output(
-  command: &String,
-  arguments: &Vec<String>,
-  workdir: &PathBuf
-) -> Output {
-  let child: Child;
+/// CommandInput is the source of the standard input of a command.
+#[derive(Debug, Clone)]
+pub enum CommandInput {
+  /// The content of the file at the given path
+  File(PathBuf),
+  /// The given bytes
+  Bytes(Vec<u8>),
+}
 
-  #[cfg(target_os = "linux")] {
-  child = tokio::process::Command::new("sh")
-    .arg("-c")
-    .arg(format!("{} {}", command, arguments.join(" ")))
-    .current_dir(workdir)
-    .stdout(std::process::Stdio::piped())
-    .spawn()
-    .expect(format!("failed to spawn command: {:?}", command).as_str());
+/// CommandSpec describes a command to run with `execute_command`.
+#[derive(Debug, Clone, Default)]
+pub struct CommandSpec {
+  pub command: String,
+  pub arguments: Vec<String>,
+  pub workdir: PathBuf,
+  /// Join the command line and run it through the shell
+  pub shell: bool,
+  /// Variables added to the environment of the command
+  pub env: HashMap<String, String>,
+  /// The standard input of the command, inherited if not set
+  pub stdin: Option<CommandInput>,
+  /// Time after which the command is killed
+  pub timeout: Option<Duration>,
+  /// The command is killed as soon as the value becomes true
+  pub cancel: Option<watch::Receiver<bool>>,
+  /// Resource limits of the command processes
+  pub limits: ResourceLimits,
+  /// The cgroup (v2) the command is placed in
+  pub cgroup: Option<PathBuf>,
+  /// The cores the command is pinned to
+  pub affinity: Option<Vec<usize>>,
+}
+
+/// ResourceLimits are the rlimits applied to the processes of a command.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceLimits {
+  /// Size (in bytes) of the virtual memory of each process
+  pub address_space: Option<u64>,
+  /// CPU time (in seconds) of each process, SIGXCPU is sent when it is exceeded
+  pub cpu_time: Option<u64>,
+  /// Number of files each process can open
+  pub open_files: Option<u64>,
+}
+
+/// Time a command has to exit after SIGTERM, before being sent SIGKILL.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/**
+ * Time the standard streams of a command are still read after it exits.
+ * A process left running in the background by the command (`cmd &`, `nohup`) may hold the pipes open forever.
+ */
+const OUTPUT_DRAIN_PERIOD: Duration = Duration::from_secs(5);
+
+/// Termination is the reason a command was killed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+  TimedOut(Duration),
+  Cancelled,
+}
+
+/**
+ * Builds the process running the command of `spec`.
+ * By default the command is spawned directly with the arguments as its argv,
+ * in `shell` mode the command line is joined and run through the shell instead.
+ */
+fn build_command(spec: &CommandSpec) -> tokio::process::Command {
+  let mut process: tokio::process::Command;
+
+  if spec.shell {
+    #[cfg(target_os = "linux")] {
+    process = tokio::process::Command::new("sh");
+    process.arg("-c");
+    }
+
+    #[cfg(target_os = "windows")] {
+    process = tokio::process::Command::new("powershell.exe");
+    process.arg("-Command");
+    }
+
+    process.arg(format!("{} {}", spec.command, spec.arguments.join(" ")));
+  } else {
+    process = tokio::process::Command::new(&spec.command);
+    process.args(&spec.arguments);
+  }
+
+  process.current_dir(&spec.workdir);
+  process.envs(&spec.env);
+
+  // the command leads its own process group, so that it can be killed with its children
+  #[cfg(unix)]
+  process.process_group(0);
+
+  #[cfg(unix)]
+  apply_limits(&mut process, spec);
+
+  match &spec.stdin {
+    Some(CommandInput::File(path)) => {
+      let file = std::fs::File::open(path)
+        .expect(format!("failed to open standard input: {:?}", path).as_str());
+      process.stdin(Stdio::from(file));
+    }
+    Some(CommandInput::Bytes(_)) => {
+      process.stdin(Stdio::piped());
+    }
+    None => {}
+  }
+
+  process
+}
+
+/**
+ * Moves the process of the command to the cgroup of `spec`, pins it to its cores
+ * and applies its limits before running the command.
+ */
+#[cfg(unix)]
+fn apply_limits(process: &mut tokio::process::Command, spec: &CommandSpec) {
+  use std::os::unix::ffi::OsStrExt;
+
+  let limits = spec.limits.clone();
+  let unlimited = limits.address_space.is_none() && limits.cpu_time.is_none() && limits.open_files.is_none();
+
+  if unlimited && spec.cgroup.is_none() && spec.affinity.is_none() {
+    return;
+  }
+
+  // prepared here, since the code running after fork should not allocate
+  let cgroup_procs = spec.cgroup.as_ref().map(|cgroup| {
+    std::ffi::CString::new(cgroup.join("cgroup.procs").as_os_str().as_bytes())
+      .expect("failed to convert cgroup path")
+  });
+
+  #[cfg(target_os = "linux")]
+  let affinity = spec.affinity.as_ref().map(super::affinity::cpu_set);
+
+  let pre_exec = move || {
+    #[cfg(target_os = "linux")]
+    if let Some(affinity) = &affinity {
+      let result = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), affinity) };
+      if result != 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+    }
+
+    if let Some(cgroup_procs) = &cgroup_procs {
+      // writing 0 moves the writing process
+      let fd = unsafe { libc::open(cgroup_procs.as_ptr(), libc::O_WRONLY) };
+      if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+
+      let written = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+      unsafe { libc::close(fd) };
+
+      if written < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+    }
+
+    // the hard limit can exceed the soft one by `grace`
+    let set_limit = |resource, value: Option<u64>, grace: u64| {
+      if let Some(value) = value {
+        let limit = libc::rlimit {
+          rlim_cur: value as libc::rlim_t,
+          rlim_max: (value + grace) as libc::rlim_t,
+        };
+
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+          return Err(std::io::Error::last_os_error());
+        }
+      }
+
+      Ok(())
+    };
+
+    set_limit(libc::RLIMIT_AS, limits.address_space, 0)?;
+    // SIGXCPU is sent at the soft limit, SIGKILL a second later at the hard one
+    set_limit(libc::RLIMIT_CPU, limits.cpu_time, 1)?;
+    set_limit(libc::RLIMIT_NOFILE, limits.open_files, 0)?;
+
+    Ok(())
+  };
+
+  unsafe { process.pre_exec(pre_exec) };
+}
+
+/**
+ * Quotes `value` so that the shell reads it as a single word.
+ */
+pub fn shell_quote(value: &String) -> String {
+  let safe = |c: char| c.is_ascii_alphanumeric() || "_-./,:=@%+".contains(c);
+
+  if !value.is_empty() && value.chars().all(safe) {
+    return value.clone();
   }
 
   #[cfg(target_os = "windows")] {
-  child = tokio::process::Command::new("powershell.exe")
-    .arg("-Command")
-    .arg(format!("{} {}", command, arguments.join(" ")))
-    .current_dir(workdir)
-    .stdout(std::process::Stdio::piped())
-    .spawn()
-    .expect(format!("failed to spawn command: {:?}", command).as_str());
+  return format!("'{}'", value.replace("'", "''"));
+  }
+
+  #[cfg(not(target_os = "windows"))] {
+  format!("'{}'", value.replace("'", "'\\''"))
   }
+}
+
+/// Name of the file (in the workdir of the command) holding its standard output.
+pub const STDOUT_LOG: &str = ".swirl.stdout.log";
+/// Name of the file (in the workdir of the command) holding its standard error.
+pub const STDERR_LOG: &str = ".swirl.stderr.log";
+
+/// Number of trailing bytes of each stream kept in memory to report failures.
+const LOG_TAIL_SIZE: usize = 4096;
+
+/// StreamLog summarizes a stream of a command written to a log file.
+#[derive(Debug)]
+pub struct StreamLog {
+  pub path: PathBuf,
+  /// Number of bytes the command wrote, including the ones over the log cap
+  pub size: u64,
+  /// The log file holds only the first `log_cap` bytes of the stream
+  pub truncated: bool,
+  /// The last bytes of the stream
+  pub tail: String,
+}
 
-  return child
-    .wait_with_output()
+#[derive(Debug)]
+pub struct CommandOutput {
+  pub status: ExitStatus,
+  /// The whole standard output, only kept if requested
+  pub stdout: Option<Vec<u8>>,
+  pub stdout_log: StreamLog,
+  pub stderr_log: StreamLog,
+  /// Set if the command was killed before exiting
+  pub termination: Option<Termination>,
+  /// A process of the command was killed for exceeding the memory limit of its cgroup
+  pub oom_killed: bool,
+}
+
+/**
+ * Copies `stream` into the log file at `path`, writing at most `log_cap` bytes.
+ * The rest of the stream is drained, so that the command never blocks on a full pipe,
+ * until the stream ends or `stop` is set.
+ */
+async fn capture_stream<R>(
+  mut stream: R,
+  path: PathBuf,
+  log_cap: u64,
+  keep: bool,
+  mut stop: watch::Receiver<bool>,
+) -> (StreamLog, Option<Vec<u8>>) where R: AsyncRead + Unpin {
+  let mut file = tokio::fs::File::create(&path)
     .await
-    .expect("failed to wait with output");
-}
-
-pub async fn execute_command(
-  command: &String,
-  arguments: &Vec<String>,
-  workdir: &PathBuf
-) -> ExitStatus {
-  let mut child: Child;
-
-  #[cfg(target_os = "linux")] {
-  child = tokio::process::Command::new("sh")
-    .arg("-c")
-    .arg(format!("{} {}", command, arguments.join(" ")))
-    .current_dir(workdir)
-    .stdout(std::process::Stdio::null())
-    .spawn()
-    .expect(format!("failed to spawn command: {:?}", command).as_str());
+    .expect(format!("failed to create log file: {:?}", path).as_str());
+
+  let mut buffer = vec![0u8; 64 * 1024];
+  let mut size: u64 = 0;
+  let mut tail: Vec<u8> = vec![];
+  let mut content = if keep { Some(vec![]) } else { None };
+
+  loop {
+    let read = tokio::select! {
+      read = stream.read(&mut buffer) => read.expect("failed to read command output"),
+      _ = stop.wait_for(|stop| *stop) => break,
+    };
+    if read == 0 {
+      break;
+    }
+
+    let chunk = &buffer[..read];
+
+    if size < log_cap {
+      let length = std::cmp::min(read as u64, log_cap - size) as usize;
+      file.write_all(&chunk[..length]).await.expect("failed to write log file");
+    }
+    size += read as u64;
+
+    tail.extend_from_slice(chunk);
+    if tail.len() > LOG_TAIL_SIZE {
+      tail.drain(..tail.len() - LOG_TAIL_SIZE);
+    }
+
+    if let Some(content) = content.as_mut() {
+      content.extend_from_slice(chunk);
+    }
   }
 
-  #[cfg(target_os = "windows")] {
-  child = tokio::process::Command::new("powershell.exe")
-    .arg("-Command")
-    .arg(format!("{} {}", command, arguments.join(" ")))
-    .current_dir(workdir)
-    .stdout(std::process::Stdio::null())
+  file.flush().await.expect("failed to flush log file");
+
+  let log = StreamLog {
+    path,
+    size,
+    truncated: size > log_cap,
+    tail: String::from_utf8_lossy(&tail).to_string(),
+  };
+
+  (log, content)
+}
+
+/**
+ * Runs the command of `spec`, writing its standard output and error to the `STDOUT_LOG`
+ * and `STDERR_LOG` files in its workdir (up to `log_cap` bytes each).
+ * The whole standard output is also returned if `keep_stdout` is set.
+ */
+pub async fn execute_command(spec: &CommandSpec, log_cap: u64, keep_stdout: bool) -> CommandOutput {
+  let mut child: Child = build_command(spec)
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
     .spawn()
-    .expect(format!("failed to spawn command: {:?}", command).as_str());
+    .expect(format!("failed to spawn command: {:?}", spec.command).as_str());
+
+  let stdin = child.stdin.take();
+  let stdout = child.stdout.take().expect("failed to take stdout");
+  let stderr = child.stderr.take().expect("failed to take stderr");
+
+  // set when the streams are no longer read, some time after the command exits
+  let (stop, stopped) = watch::channel(false);
+
+  let mut stdin_stopped = stopped.clone();
+  let feed_stdin = async move {
+    if let (Some(mut stdin), Some(CommandInput::Bytes(bytes))) = (stdin, &spec.stdin) {
+      let written = tokio::select! {
+        written = stdin.write_all(bytes) => written,
+        _ = stdin_stopped.wait_for(|stop| *stop) => Ok(()),
+      };
+
+      // the command may exit without reading the whole input
+      match written {
+        Err(error) if error.kind() != std::io::ErrorKind::BrokenPipe => {
+          panic!("failed to write standard input: {:?}", error);
+        }
+        _ => {}
+      }
+    }
+  };
+
+  let wait = async move {
+    let deadline = async {
+      match spec.timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+      }
+    };
+
+    let cancelled = async {
+      match spec.cancel.clone() {
+        Some(mut cancel) => {
+          if cancel.wait_for(|cancelled| *cancelled).await.is_err() {
+            // nobody can cancel the command anymore
+            std::future::pending::<()>().await;
+          }
+        }
+        None => std::future::pending().await,
+      }
+    };
+
+    let termination = tokio::select! {
+      status = child.wait() => {
+        return (status.expect("failed to wait for command"), None);
+      }
+      _ = deadline => Termination::TimedOut(spec.timeout.expect("failed to get timeout")),
+      _ = cancelled => Termination::Cancelled,
+    };
+
+    (kill_process_group(&mut child).await, Some(termination))
+  };
+
+  let wait = async move {
+    let result = wait.await;
+
+    // the streams still open after the drain period are held by a process the command left behind
+    tokio::select! {
+      _ = tokio::time::sleep(OUTPUT_DRAIN_PERIOD) => {
+        let _ = stop.send(true);
+      }
+      _ = stop.closed() => {}
+    }
+
+    result
+  };
+
+  let (_, (stdout_log, stdout), (stderr_log, _), (status, termination)) = tokio::join!(
+    feed_stdin,
+    capture_stream(stdout, spec.workdir.join(STDOUT_LOG), log_cap, keep_stdout, stopped.clone()),
+    capture_stream(stderr, spec.workdir.join(STDERR_LOG), log_cap, false, stopped),
+    wait,
+  );
+
+  let oom_killed = match &spec.cgroup {
+    Some(cgroup) => super::cgroup::oom_killed(cgroup),
+    None => false,
+  };
+
+  CommandOutput {
+    status,
+    stdout,
+    stdout_log,
+    stderr_log,
+    termination,
+    oom_killed,
   }
+}
 
-  return child
-    .wait()
-    .await
-    .expect("failed to wait with output");
-}
\ No newline at end of file
+/**
+ * Kills the process group led by `child`: first with SIGTERM and,
+ * if it is still running after `KILL_GRACE_PERIOD`, with SIGKILL.
+ */
+async fn kill_process_group(child: &mut Child) -> ExitStatus {
+  #[cfg(unix)] {
+  let pid = child.id().expect("failed to get process id") as i32;
+
+  unsafe { libc::kill(-pid, libc::SIGTERM) };
+
+  if let Ok(status) = tokio::time::timeout(KILL_GRACE_PERIOD, child.wait()).await {
+    return status.expect("failed to wait for command");
+  }
+
+  unsafe { libc::kill(-pid, libc::SIGKILL) };
+  }
+
+  #[cfg(not(unix))] {
+  child.start_kill().expect("failed to kill command");
+  }
+
+  child.wait().await.expect("failed to wait for command")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("swirl_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir
+  }
+
+  fn shell_spec(command: &str, workdir: &PathBuf) -> CommandSpec {
+    CommandSpec {
+      command: command.to_string(),
+      workdir: workdir.clone(),
+      shell: true,
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn output_is_captured() {
+    let workdir = temp_dir();
+    let mut spec = shell_spec("cat; echo error >&2", &workdir);
+    spec.stdin = Some(CommandInput::Bytes(b"input".to_vec()));
+
+    let output = execute_command(&spec, 1024, true).await;
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, Some(b"input".to_vec()));
+    assert_eq!(output.stderr_log.tail, "error\n");
+    assert_eq!(std::fs::read_to_string(workdir.join(STDOUT_LOG)).unwrap(), "input");
+
+    std::fs::remove_dir_all(&workdir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn background_process_does_not_hold_the_command() {
+    let workdir = temp_dir();
+    let spec = shell_spec("sleep 30 & echo done", &workdir);
+
+    let start = std::time::Instant::now();
+    let output = execute_command(&spec, 1024, true).await;
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, Some(b"done\n".to_vec()));
+    assert!(start.elapsed() < OUTPUT_DRAIN_PERIOD + Duration::from_secs(5));
+
+    std::fs::remove_dir_all(&workdir).unwrap();
+  }
+}
@@ -1,11 +1,11 @@
 use std::{path::PathBuf, sync::Arc};
 
 use bytes::Bytes;
-use tokio::{io::BufReader, task::JoinSet};
+use tokio::{io::{AsyncReadExt, BufReader}, task::JoinSet};
 
-use crate::orchestra::{utils::debug_prelude, LocationID};
+use crate::orchestra::{utils::{archive, debug_prelude, paths_size}, LocationID};
 
-use super::{PortData, PortID, Swirl};
+use super::{encode_manifest, plan::skipped, sequence::message_id, watchdog::Operation, PortData, PortHeader, PortID, Swirl};
 
 impl Swirl {
   pub async fn broadcast(
@@ -14,6 +14,26 @@ impl Swirl {
     destinations: Vec<String>,
     mut join_set: JoinSet<()>,
   ) -> JoinSet<()> {
+    if self.options.dry_run {
+      self.plan(format!("broadcast {} to {}", port_id, destinations.join(", ")));
+      return join_set;
+    }
+
+    // a broadcast sends the same value to every destination, so they must have received as many values
+    // of the port: otherwise the value is sent to each destination on its own
+    let seq = match self.sequences.next_broadcast(&port_id, &destinations) {
+      Some(seq) => seq,
+      None => {
+        for destination in destinations {
+          join_set = self.send(port_id.clone(), destination, join_set).await;
+        }
+
+        return join_set;
+      }
+    };
+    let message = message_id(&port_id, seq);
+
+    let destination_names = destinations.clone();
     let destinations = destinations
       .iter()
       .map(|d| self.orchestra.location_id(d))
@@ -24,12 +44,11 @@ impl Swirl {
 
     let port = self.ports.get(&port_id).expect("port not found");
 
-    port.wait_for_data().await;
-
-    let data_read = port.value.read().await;
-    let data = data_read.clone();
-
-    drop(data_read);
+    // a skipped group sends skipped data, whatever the port holds
+    let data = match skipped() {
+      true => PortData::Skip,
+      false => port.wait_for_data().await,
+    };
 
     match data {
       PortData::File(path) => {
@@ -38,6 +57,11 @@ impl Swirl {
         let required_permits = 1 + destinations.len() as u32;
 
         join_set.spawn(async move {
+          let _sending = swirl.track(Operation::Send {
+            port: port_id.clone(),
+            destinations: destination_names.clone(),
+          });
+
           let permit = swirl.connection_limit.acquire_many(required_permits).await;
 
           let file_name = PathBuf::from(&path)
@@ -58,7 +82,7 @@ impl Swirl {
           let file_size = file.metadata().await.unwrap().len() as usize;
 
 
-          let header_data = PortData::File(file_name);
+          let header_data = PortHeader::File(file_name);
           let header_data = bincode::serialize(&header_data).unwrap();
           let header_data = Bytes::from(header_data);
 
@@ -66,7 +90,7 @@ impl Swirl {
 
           swirl
             .orchestra
-            .broadcast_blocking(destinations, port_id, reader, header_data, file_size)
+            .broadcast_blocking(destinations, message.clone(), reader, header_data, file_size)
             .await;
 
           println!(
@@ -81,6 +105,63 @@ impl Swirl {
 
         return join_set;
       }
+      data @ (PortData::Directory(_) | PortData::Files(_)) => {
+        let swirl = self.clone();
+
+        let (paths, names) = data.archive_entries().unwrap();
+
+        let header_data = match data {
+          PortData::Directory(_) => PortHeader::Directory,
+          _ => PortHeader::Files,
+        };
+
+        let required_permits = 1 + destinations.len() as u32;
+
+        join_set.spawn(async move {
+          let _sending = swirl.track(Operation::Send {
+            port: port_id.clone(),
+            destinations: destination_names.clone(),
+          });
+
+          let permit = swirl.connection_limit.acquire_many(required_permits).await;
+
+          let description = match &header_data {
+            PortHeader::Directory => format!("directory {}", names[0]),
+            _ => format!("{} files", names.len()),
+          };
+
+          let task = swirl.amdahline.begin_task(&location, &format!("broadcast {}", description));
+
+          // the data is streamed as a tar archive after its manifest, the size is only indicative
+          let manifest = encode_manifest(&names);
+          let size = manifest.len() + paths_size(&paths);
+          let mut tar = archive(&paths);
+          let reader = std::io::Cursor::new(manifest).chain(tar.stdout.take().expect("failed to get archive output"));
+
+          let header_data = bincode::serialize(&header_data).unwrap();
+          let header_data = Bytes::from(header_data);
+
+          swirl
+            .orchestra
+            .broadcast_blocking(destinations, message.clone(), reader, header_data, size)
+            .await;
+
+          let status = tar.wait().await.expect("failed to wait for archive");
+          assert!(status.success(), "failed to archive {:?}: {}", paths, status);
+
+          println!(
+            "{} Completed broadcast of {}",
+            debug_prelude(&swirl.orchestra.self_name(), None),
+            description
+          );
+
+          swirl.amdahline.end_task(&location, task);
+
+          drop(permit);
+        });
+
+        return join_set;
+      }
       PortData::Empty => {
         println!(
           "{} PANIC: empty data",
@@ -89,17 +170,24 @@ impl Swirl {
         panic!("empty data");
       }
       data => {
+        // the value is the message body, the header only tells its kind
         let data = bincode::serialize(&data).unwrap();
         let data_size = data.len();
+        let header_data = Bytes::from(bincode::serialize(&PortHeader::Value).unwrap());
 
-        let join_set = self.orchestra.broadcast_joinset(
-          destinations,
-          port_id,
-          tokio::io::empty(),
-          Bytes::from(data),
-          data_size,
-          join_set,
-        );
+        let swirl = self.clone();
+
+        join_set.spawn(async move {
+          let _sending = swirl.track(Operation::Send {
+            port: port_id.clone(),
+            destinations: destination_names.clone(),
+          });
+
+          swirl
+            .orchestra
+            .broadcast_blocking(destinations, message.clone(), std::io::Cursor::new(data), header_data, data_size)
+            .await;
+        });
 
         println!(
           "{} Completed broadcast of data",
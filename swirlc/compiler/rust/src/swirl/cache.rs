@@ -0,0 +1,367 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use sha2::{Digest, Sha256};
+
+use crate::orchestra::utils::{CommandInput, CommandSpec};
+
+use super::{PortData, PortID, StepOutput, Swirl};
+
+/// Name of the file (in a cache entry) holding the outputs of the step, with paths relative to its workdir.
+const CACHE_OUTPUTS: &str = "outputs.json";
+/// Name of the directory (in a cache entry) holding the output files of the step.
+const CACHE_FILES: &str = "files";
+
+impl Swirl {
+  /// Returns the directory holding the cached step results of this location, the run script keeps it when clearing the workdir.
+  pub fn cache_dir(&self) -> PathBuf {
+    self.workdir.join("cache")
+  }
+
+  /**
+   * Returns the key of the results of running `spec` on the data of `input_ports`
+   * and collecting `outputs`: the hash of the command, its arguments, environment,
+   * standard input and the content of the input data.
+   * The input files reuse the content hashes computed to send them, the directories are hashed here.
+   * BLOCKING: waits for the data of the input ports.
+   */
+  pub(super) async fn cache_key(
+    &self,
+    spec: &CommandSpec,
+    input_ports: &Vec<PortID>,
+    outputs: &Vec<(PortID, StepOutput)>,
+  ) -> String {
+    let mut inputs = vec![];
+    let mut file_hashes = HashMap::new();
+    for input_port in input_ports {
+      let port = self.ports.get(input_port).expect("port not found");
+      let data = port.wait_for_data().await;
+
+      for path in data_paths(&data) {
+        if Path::new(&path).is_file() {
+          file_hashes.insert(path.clone(), self.content_hash(&path).await);
+        }
+      }
+
+      inputs.push((input_port.clone(), data));
+    }
+
+    if let Some(CommandInput::File(path)) = &spec.stdin {
+      let path = path_string(path);
+      if Path::new(&path).is_file() {
+        file_hashes.insert(path.clone(), self.content_hash(&path).await);
+      }
+    }
+
+    let spec = spec.clone();
+    let outputs = format!("{:?}", outputs);
+
+    // the input files are hashed, which might take a while
+    tokio::task::spawn_blocking(move || key_hash(&spec, &inputs, &outputs, &file_hashes))
+      .await
+      .expect("failed to join hashing task")
+  }
+
+  /**
+   * Copies the output files of the cache entry `key` into the step workdir and returns the outputs,
+   * None if there is no such entry.
+   */
+  pub(super) fn restore_cached(&self, key: &String, step_workdir: &PathBuf) -> Option<Vec<(PortID, PortData)>> {
+    let entry = self.cache_dir().join(key);
+
+    let outputs = std::fs::read_to_string(entry.join(CACHE_OUTPUTS)).ok()?;
+    let outputs: Vec<(PortID, PortData)> = serde_json::from_str(&outputs).ok()?;
+
+    let mut restored = vec![];
+    for (output_port, data) in outputs {
+      for path in data_paths(&data) {
+        copy_tree(&entry.join(CACHE_FILES).join(&path), &step_workdir.join(&path));
+      }
+
+      let data = map_paths(&data, &|path| path_string(&step_workdir.join(path)));
+      restored.push((output_port, data));
+    }
+
+    Some(restored)
+  }
+
+  /**
+   * Stores the `outputs` collected from the step workdir as the cache entry `key`.
+   */
+  pub(super) fn store_cached(&self, key: &String, step_workdir: &PathBuf, outputs: &Vec<(PortID, PortData)>) {
+    let mut relative_outputs = vec![];
+    for (output_port, data) in outputs {
+      // only the outputs in the step workdir can be cached
+      let outside = data_paths(data)
+        .iter()
+        .any(|path| !Path::new(path).starts_with(step_workdir));
+      if outside {
+        return;
+      }
+
+      let data = map_paths(data, &|path| {
+        path_string(Path::new(path).strip_prefix(step_workdir).expect("failed to strip prefix"))
+      });
+      relative_outputs.push((output_port.clone(), data));
+    }
+
+    // the entry is written aside and then renamed, so that it is never read half written
+    let entry = self.cache_dir().join(key);
+    let partial = self.cache_dir().join(format!("{}.{}.partial", key, uuid::Uuid::new_v4()));
+
+    std::fs::create_dir_all(partial.join(CACHE_FILES)).expect("failed to create cache entry");
+
+    for (_, data) in &relative_outputs {
+      for path in data_paths(data) {
+        copy_tree(&step_workdir.join(&path), &partial.join(CACHE_FILES).join(&path));
+      }
+    }
+
+    let outputs = serde_json::to_string(&relative_outputs).expect("failed to serialize outputs");
+    std::fs::write(partial.join(CACHE_OUTPUTS), outputs).expect("failed to write cache entry");
+
+    if std::fs::rename(&partial, &entry).is_err() {
+      // the same entry was stored in the meantime
+      let _ = std::fs::remove_dir_all(&partial);
+    }
+  }
+}
+
+/**
+ * Returns the hash of the command, arguments, environment and standard input of `spec`,
+ * of the data of the `inputs` and of the description of the `outputs`.
+ * The files in `file_hashes` are not hashed again.
+ */
+fn key_hash(
+  spec: &CommandSpec,
+  inputs: &Vec<(PortID, PortData)>,
+  outputs: &String,
+  file_hashes: &HashMap<String, String>,
+) -> String {
+  let mut hasher = Sha256::new();
+
+  update(&mut hasher, "command", spec.command.as_bytes());
+  for argument in &spec.arguments {
+    update(&mut hasher, "argument", argument.as_bytes());
+  }
+  update(&mut hasher, "shell", spec.shell.to_string().as_bytes());
+
+  let mut env = spec.env.iter().collect::<Vec<_>>();
+  env.sort();
+  for (name, value) in env {
+    update(&mut hasher, "env", format!("{}={}", name, value).as_bytes());
+  }
+
+  match &spec.stdin {
+    Some(CommandInput::File(path)) => update(&mut hasher, "stdin", tree_hash(path, file_hashes).as_bytes()),
+    Some(CommandInput::Bytes(bytes)) => update(&mut hasher, "stdin", bytes),
+    None => {}
+  }
+
+  for (input_port, data) in inputs {
+    update(&mut hasher, "input", input_port.as_bytes());
+    update(&mut hasher, "data", data_hash(data, file_hashes).as_bytes());
+  }
+
+  update(&mut hasher, "outputs", outputs.as_bytes());
+
+  format!("{:x}", hasher.finalize())
+}
+
+fn update(hasher: &mut Sha256, field: &str, value: &[u8]) {
+  hasher.update(field.as_bytes());
+  hasher.update((value.len() as u64).to_le_bytes());
+  hasher.update(value);
+}
+
+/// Returns the hash of `data`, files and directories are hashed by name and content.
+fn data_hash(data: &PortData, file_hashes: &HashMap<String, String>) -> String {
+  let mut hasher = Sha256::new();
+
+  match data {
+    PortData::File(path) | PortData::Directory(path) => {
+      update(&mut hasher, "name", file_name(path).as_bytes());
+      update(&mut hasher, "content", tree_hash(Path::new(path), file_hashes).as_bytes());
+    }
+    PortData::Files(paths) => {
+      for path in paths {
+        update(&mut hasher, "name", file_name(path).as_bytes());
+        update(&mut hasher, "content", tree_hash(Path::new(path), file_hashes).as_bytes());
+      }
+    }
+    PortData::List(values) => {
+      for value in values {
+        update(&mut hasher, "value", data_hash(value, file_hashes).as_bytes());
+      }
+    }
+    data => {
+      let value = serde_json::to_string(data).expect("failed to serialize data");
+      update(&mut hasher, "value", value.as_bytes());
+    }
+  }
+
+  format!("{:x}", hasher.finalize())
+}
+
+/**
+ * Returns the hash of the content of the file at `path`, or of the names and contents of the directory at `path`.
+ * The hashes of files already in `file_hashes` are not computed again.
+ */
+fn tree_hash(path: &Path, file_hashes: &HashMap<String, String>) -> String {
+  if let Some(hash) = file_hashes.get(&path_string(path)) {
+    return hash.clone();
+  }
+
+  let mut hasher = Sha256::new();
+
+  if path.is_dir() {
+    let mut entries = std::fs::read_dir(path)
+      .expect(format!("failed to read dir: {:?}", path).as_str())
+      .map(|entry| entry.expect("failed to read dir entry").path())
+      .collect::<Vec<_>>();
+    entries.sort();
+
+    for entry in entries {
+      update(&mut hasher, "name", file_name(&path_string(&entry)).as_bytes());
+      update(&mut hasher, "content", tree_hash(&entry, file_hashes).as_bytes());
+    }
+  } else {
+    let mut file = std::fs::File::open(path).expect(format!("failed to open file: {:?}", path).as_str());
+    std::io::copy(&mut file, &mut hasher).expect("failed to hash file");
+  }
+
+  format!("{:x}", hasher.finalize())
+}
+
+/**
+ * Copies the file (or the files of the directory) at `source` to `destination`.
+ * Cache entries are never linked to the step workdirs, where a step could modify them in place.
+ */
+fn copy_tree(source: &Path, destination: &Path) {
+  if let Some(parent) = destination.parent() {
+    std::fs::create_dir_all(parent).expect("failed to create directory");
+  }
+
+  if source.is_dir() {
+    std::fs::create_dir_all(destination).expect("failed to create directory");
+
+    for entry in std::fs::read_dir(source).expect(format!("failed to read dir: {:?}", source).as_str()) {
+      let entry = entry.expect("failed to read dir entry");
+      copy_tree(&entry.path(), &destination.join(entry.file_name()));
+    }
+    return;
+  }
+
+  let _ = std::fs::remove_file(destination);
+
+  std::fs::copy(source, destination)
+    .expect(format!("failed to copy {:?} to {:?}", source, destination).as_str());
+}
+
+/// Returns the paths held by `data`.
+fn data_paths(data: &PortData) -> Vec<String> {
+  match data {
+    PortData::File(path) | PortData::Directory(path) => vec![path.clone()],
+    PortData::Files(paths) => paths.clone(),
+    PortData::List(values) => values.iter().flat_map(data_paths).collect(),
+    _ => vec![],
+  }
+}
+
+/// Returns `data` with each of its paths replaced by `map(path)`.
+fn map_paths(data: &PortData, map: &dyn Fn(&String) -> String) -> PortData {
+  match data {
+    PortData::File(path) => PortData::File(map(path)),
+    PortData::Directory(path) => PortData::Directory(map(path)),
+    PortData::Files(paths) => PortData::Files(paths.iter().map(map).collect()),
+    PortData::List(values) => PortData::List(values.iter().map(|value| map_paths(value, map)).collect()),
+    data => data.clone(),
+  }
+}
+
+fn file_name(path: &String) -> String {
+  PathBuf::from(path)
+    .file_name()
+    .map(|name| name.to_string_lossy().to_string())
+    .unwrap_or_default()
+}
+
+fn path_string(path: &Path) -> String {
+  path.to_str().expect("failed to convert to string").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("swirl_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir
+  }
+
+  fn spec(arguments: &[&str], env: &[(&str, &str)]) -> CommandSpec {
+    CommandSpec {
+      command: "cat".to_string(),
+      arguments: arguments.iter().map(|argument| argument.to_string()).collect(),
+      env: env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+      ..Default::default()
+    }
+  }
+
+  fn file_input(path: &PathBuf) -> Vec<(PortID, PortData)> {
+    vec![("p1".into(), PortData::File(path_string(path)))]
+  }
+
+  #[test]
+  fn key_depends_on_the_command() {
+    let outputs = "outputs".to_string();
+    let inputs = vec![("p1".into(), PortData::Int(1))];
+    let key = |spec: &CommandSpec, inputs: &Vec<(PortID, PortData)>| key_hash(spec, inputs, &outputs, &HashMap::new());
+
+    let base = key(&spec(&["a", "b"], &[("X", "1"), ("Y", "2")]), &inputs);
+
+    // the order of the environment variables does not matter
+    assert_eq!(base, key(&spec(&["a", "b"], &[("Y", "2"), ("X", "1")]), &inputs));
+
+    assert_ne!(base, key(&spec(&["a", "c"], &[("X", "1"), ("Y", "2")]), &inputs));
+    assert_ne!(base, key(&spec(&["ab"], &[("X", "1"), ("Y", "2")]), &inputs));
+    assert_ne!(base, key(&spec(&["a", "b"], &[("X", "1")]), &inputs));
+    assert_ne!(base, key(&spec(&["a", "b"], &[("X", "1"), ("Y", "2")]), &vec![("p1".into(), PortData::Int(2))]));
+    assert_ne!(base, key_hash(&spec(&["a", "b"], &[("X", "1"), ("Y", "2")]), &inputs, &"other".to_string(), &HashMap::new()));
+  }
+
+  #[test]
+  fn key_depends_on_the_name_and_content_of_the_files() {
+    let dir = temp_dir();
+    let outputs = "outputs".to_string();
+    let spec = spec(&["input.txt"], &[]);
+
+    for (subdir, name, content) in [("a", "input.txt", "x"), ("b", "input.txt", "x"), ("c", "input.txt", "y"), ("d", "other.txt", "x")] {
+      std::fs::create_dir_all(dir.join(subdir)).unwrap();
+      std::fs::write(dir.join(subdir).join(name), content).unwrap();
+    }
+
+    let key = |path: PathBuf| key_hash(&spec, &file_input(&path), &outputs, &HashMap::new());
+    let base = key(dir.join("a/input.txt"));
+
+    // the same file received into another directory
+    assert_eq!(base, key(dir.join("b/input.txt")));
+    assert_ne!(base, key(dir.join("c/input.txt")));
+    assert_ne!(base, key(dir.join("d/other.txt")));
+
+    // directories are hashed by the names and contents of their files
+    for (subdir, content) in [("e", "x"), ("f", "x"), ("g", "y")] {
+      std::fs::create_dir_all(dir.join(subdir).join("data")).unwrap();
+      std::fs::write(dir.join(subdir).join("data/input.txt"), content).unwrap();
+    }
+    assert_eq!(key(dir.join("e/data")), key(dir.join("f/data")));
+    assert_ne!(key(dir.join("e/data")), key(dir.join("g/data")));
+
+    // the known content hashes are used instead of the content
+    let path = path_string(&dir.join("a/input.txt"));
+    let file_hashes = HashMap::from([(path, "known".to_string())]);
+    assert_ne!(base, key_hash(&spec, &file_input(&dir.join("a/input.txt")), &outputs, &file_hashes));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,137 @@
+use std::{future::Future, pin::Pin, sync::{atomic::Ordering, Arc}};
+
+use tokio::task::JoinSet;
+
+use crate::orchestra::utils::debug_prelude;
+
+use super::{plan::inherit_skipped, sequence::message_id, watchdog::Operation, PortID, Swirl};
+
+#[derive(Debug, Clone)]
+/// Guard is the first operation of a branch of a choice, which decides when the branch can be taken.
+pub enum Guard {
+  /// The branch starts by receiving the port from the sender, it can be taken when the data arrives
+  Receive { port: PortID, sender: String },
+  /// The branch can be taken right away
+  Ready,
+}
+
+impl std::fmt::Display for Guard {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Guard::Receive { port, sender } => write!(f, "receive of port {} from {}", port, sender),
+      Guard::Ready => write!(f, "ready"),
+    }
+  }
+}
+
+/// Branch is a branch of a choice, the operations run if its guard is the first to be ready.
+pub struct Branch {
+  guard: Guard,
+  body: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Branch {
+  pub fn new<F>(guard: Guard, body: F) -> Self
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    Branch {
+      guard,
+      body: Box::pin(body),
+    }
+  }
+}
+
+impl Swirl {
+  /**
+   * Runs the branch of the choice `choice` (its index in the location) whose guard is the first to be ready,
+   * the other branches are dropped without running. The branch taken is journaled, so that a resumed run takes it again.
+   * In a dry run, every branch is walked inline, so that the plan of each is printed.
+   * `NON-BLOCKING`: adds a task to the `JoinSet` and returns the updated `JoinSet`.
+   */
+  pub async fn choice(self: &Arc<Self>, mut join_set: JoinSet<()>, choice: usize, branches: Vec<Branch>) -> JoinSet<()> {
+    if self.options.dry_run {
+      self.plan(format!("choice {} {{", choice));
+
+      for branch in branches {
+        self.plan(format!("on {}", branch.guard));
+        self.plan_depth.fetch_add(1, Ordering::SeqCst);
+
+        branch.body.await;
+
+        self.plan_depth.fetch_sub(1, Ordering::SeqCst);
+      }
+
+      self.plan("}".to_string());
+      return join_set;
+    }
+
+    let swirl = self.clone();
+
+    join_set.spawn(inherit_skipped(async move {
+      let (guards, mut bodies): (Vec<_>, Vec<_>) = branches
+        .into_iter()
+        .map(|branch| (branch.guard, branch.body))
+        .unzip();
+
+      let resumed = match swirl.options.resume {
+        true => swirl.resume_choice(choice),
+        false => None,
+      };
+
+      let taken = match resumed {
+        // the branch taken before the interruption is taken again
+        Some(taken) => taken,
+        None => swirl.first_ready(&guards).await,
+      };
+
+      swirl.complete_choice(choice, taken);
+
+      println!(
+        "{} Choice {}: taking branch {} of {} on {}",
+        debug_prelude(&swirl.orchestra.self_name(), None),
+        choice,
+        taken + 1,
+        guards.len(),
+        guards[taken]
+      );
+
+      let body = bodies.swap_remove(taken);
+      drop(bodies);
+
+      body.await;
+    }));
+
+    join_set
+  }
+
+  /**
+   * Returns the index of the first of `guards` to be ready, the guards ready right away are taken in order.
+   * The data of a receive is only waited for, it is received by the branch taken.
+   * `BLOCKING`: waits for a guard to be ready.
+   */
+  async fn first_ready(self: &Arc<Self>, guards: &Vec<Guard>) -> usize {
+    let _choosing = self.track(Operation::Choice {
+      guards: guards.iter().map(|guard| guard.to_string()).collect(),
+    });
+
+    let mut indexes = vec![];
+    let mut messages = vec![];
+
+    for (index, guard) in guards.iter().enumerate() {
+      match guard {
+        Guard::Ready => return index,
+        Guard::Receive { port, sender } => {
+          let seq = self.sequences.peek_receive(port, sender);
+
+          indexes.push(index);
+          messages.push((self.orchestra.location_id(sender), message_id(port, seq)));
+        }
+      }
+    }
+
+    assert!(!messages.is_empty(), "choice without branches");
+
+    indexes[self.orchestra.wait_for_first_message(&messages).await]
+  }
+}
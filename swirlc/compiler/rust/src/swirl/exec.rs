@@ -1,8 +1,8 @@
-use std::{ops::Deref, path::PathBuf};
+use std::{path::PathBuf, time::Duration};
 
-use crate::{orchestra::utils::{self, debug_prelude}, swirl::{PortData}};
+use crate::{orchestra::{affinity, cgroup::StepCgroup, utils::{self, debug_prelude, CommandInput, CommandSpec, Termination}}, swirl::{PortData}};
 
-use super::{PortID, StepArgument, StepOutput, Swirl};
+use super::{plan::skipped, unique_file_names, watchdog::Operation, FailurePolicy, PortID, StepArgument, StepOptions, StepOutput, Swirl};
 
 
 impl Swirl {
@@ -11,12 +11,26 @@ impl Swirl {
     step_name: String,
     step_display_name: String,
     input_ports: Vec<PortID>,
-    output_port: Option<PortID>,
-    output_type: StepOutput,
+    outputs: Vec<(PortID, StepOutput)>,
     cmd: String,
     args: Vec<StepArgument>,
+    step_options: StepOptions,
   ) {
+    if self.options.dry_run {
+      self.plan_exec(&step_name, &step_display_name, &outputs, &cmd, &args, &step_options).await;
+      return;
+    }
+
+    if self.options.resume && self.resume_exec(&step_name).await {
+      return;
+    }
+
     let mut step_workdir = self.workdir.join(format!("step_{}", step_name));
+
+    // the files of an interrupted run of the step are discarded
+    if self.options.resume && step_workdir.exists() {
+      std::fs::remove_dir_all(&step_workdir).expect("failed to reset step workdir");
+    }
   
     let step_workdir_str = step_workdir.to_str().expect("failed to convert to string");
     let step_workdir_str = format!("failed to convert to string: {:?}", step_workdir_str);
@@ -27,41 +41,46 @@ impl Swirl {
       .canonicalize()
       .expect(format!("failed to canonicalize {:?}", step_workdir).as_str());
   
-    // loop over the ports
-    for input_port in input_ports {
-      let port = self.ports.get(&input_port).expect("port not found");
-  
-      port.wait_for_data().await;
-  
-      let data = port.value.read().await;
-      let data = data.deref();
-  
-      match data {
-        PortData::File(path) => {
-          // link the file to the step workdir
-          let file_path = PathBuf::from(path);
-          let file_name = file_path
-            .file_name()
-            .expect("failed to get file name")
-            .to_str()
-            .expect("failed to convert to string")
-            .to_string();
-  
-          let new_path = step_workdir.join(&file_name);
-          
-  
-          // create symlink
-          #[cfg(unix)]
-          {
-            std::os::unix::fs::symlink(&file_path, &new_path).expect("failed to create symlink");
-          }
-        }
-        PortData::Empty => {
-          panic!("empty data");
-        }
-        _ => {}
+    // the steps of a skipped group are skipped as well
+    if skipped() {
+      println!(
+        "{} Skipping step: {}, its group is skipped",
+        debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+        step_display_name,
+      );
+
+      self.skip_outputs(&step_name, &outputs).await;
+      return;
+    }
+
+    // a step reading skipped data is skipped as well
+    if let Some(input_port) = self.skipped_input(&input_ports).await {
+      println!(
+        "{} Skipping step: {}, the data of port {} is skipped",
+        debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+        step_display_name,
+        input_port,
+      );
+
+      self.skip_outputs(&step_name, &outputs).await;
+      return;
+    }
+
+    if let Some(condition) = &step_options.when {
+      if !self.condition(condition).await {
+        println!(
+          "{} Skipping step: {}, the condition {} is false",
+          debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+          step_display_name,
+          condition,
+        );
+
+        self.skip_outputs(&step_name, &outputs).await;
+        return;
       }
     }
+
+    let mut staged = self.stage_inputs(&input_ports, &step_workdir).await;
   
     //======================== Build arguments ========================
     let mut arguments: Vec<String> = vec![];
@@ -74,106 +93,277 @@ impl Swirl {
         StepArgument::Port(port_id) => {
           let port = self.ports.get(&port_id).expect("port not found");
   
-          port.wait_for_data().await;
+          let data = port.wait_for_data().await;
   
-          let data = port.value.read().await;
-          let data = data.deref();
-  
-          match data {
-            PortData::File(path) => {
-              // if the argument is a file, the file should be already linked to the step workdir
-              let filename = PathBuf::from(path)
-                .file_name()
-                .expect("failed to get file name")
-                .to_str()
-                .expect("failed to convert to string")
-                .to_string();
-              arguments.push(filename);
-            }
-            PortData::String(value) => {
-              arguments.push(value.clone());
-            }
-            PortData::Int(value) => {
-              arguments.push(value.to_string());
-            }
-            PortData::Bool(value) => {
-              arguments.push(value.to_string());
-            }
-            PortData::Empty => {
-              panic!("empty data");
-            }
+          let mut values = vec![];
+          render_argument(&data, &mut values);
+
+          // in shell mode the values are quoted, so that they are not interpreted by the shell
+          if step_options.shell {
+            values = values.iter().map(utils::shell_quote).collect();
           }
+
+          arguments.extend(values);
         }
       }
     }
   
-    //======================== Execute Command ========================
-    println!(
-      "{} Running command: '{} {}'",
-      debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
-      cmd,
-      arguments.join(" ")
-    );
+    //======================== Standard input ========================
+    let stdin = match &step_options.stdin {
+      Some(StepArgument::String(value)) => Some(CommandInput::Bytes(value.clone().into_bytes())),
+      Some(StepArgument::Port(port_id)) => {
+        let port = self.ports.get(port_id).expect("port not found");
+
+        match port.wait_for_data().await {
+          // files are streamed from where they are stored
+          PortData::File(path) => Some(CommandInput::File(PathBuf::from(path))),
+          data => {
+            // other values are rendered as they would be as arguments, one per line
+            let mut values = vec![];
+            render_argument(&data, &mut values);
 
+            Some(CommandInput::Bytes(values.join("\n").into_bytes()))
+          }
+        }
+      }
+      None => None,
+    };
+
+    //======================== Execute Command ========================
     let location = self.orchestra.location;
     let location  = self.orchestra.location_name(location);
-    let task = self.amdahline.begin_task(&location, &step_display_name);
   
-    let (output, status) = match output_type {
-      StepOutput::Stdout => {
-        let output = utils::execute_command_output(&cmd, &arguments, &step_workdir).await;
-        let status = output.status;
+    // the standard output is captured only if an output port reads from it
+    let capture_stdout = outputs
+      .iter()
+      .any(|(_, output_type)| matches!(output_type, StepOutput::Stdout(_)));
 
-        println!(
-          "{} Completed step: {} with status: {}",
-          debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
-          step_display_name,
-          status,
-        );
+    let retry = step_options.retry;
+
+    let mut spec = CommandSpec {
+      command: cmd,
+      arguments,
+      workdir: step_workdir.clone(),
+      shell: step_options.shell,
+      env: step_options.env,
+      stdin,
+      timeout: step_options
+        .timeout
+        .or(self.options.step_timeout.map(Duration::from_secs_f64)),
+      cancel: Some(self.steps_cancelled.subscribe()),
+      limits: step_options.limits,
+      cgroup: None,
+      affinity: None,
+    };
+
+    //======================== Cached results ========================
+    // a step without outputs is run for its side effects, which are not cached
+    let has_outputs = outputs.iter().any(|(_, output)| !matches!(output, StepOutput::None));
+
+    let cache_key = match !self.options.no_cache && has_outputs {
+      true => Some(self.cache_key(&spec, &input_ports, &outputs).await),
+      false => None,
+    };
+
+    if let Some(cached) = cache_key.as_ref().and_then(|key| self.restore_cached(key, &step_workdir)) {
+      println!(
+        "{} Reusing the cached outputs of step: {}",
+        debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+        step_display_name,
+      );
+
+      let task = self.amdahline.begin_task(&location, &step_display_name);
+      self.amdahline.annotate_task(&location, task, "cache_hit", cache_key.as_ref().expect("failed to get cache key"));
+
+      self.complete_exec(&step_name, cached).await;
+
+      self.amdahline.end_task(&location, task);
+      return;
+    }
 
-        (Some(output), status)
+    let mut attempt = 1;
+
+    let (output, task, succeeded) = loop {
+      // wait for the cores and memory of the step to be free
+      let resources = self.resources.acquire(step_options.cores, step_options.memory).await;
+
+      let attempts = match retry.max_attempts {
+        1 => "".to_string(),
+        max_attempts => format!(" (attempt {} of {})", attempt, max_attempts),
+      };
+
+      println!(
+        "{} Running command: '{} {}'{}",
+        debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+        spec.command,
+        spec.arguments.join(" "),
+        attempts,
+      );
+
+      // each attempt runs in a new cgroup, limited to the reserved resources
+      let cgroup = match self.options.cgroups {
+        true => StepCgroup::create(
+          &format!("swirl_{}_{}_{}", location, step_name, attempt),
+          (resources.memory > 0).then(|| resources.memory * 1024 * 1024),
+          Some(resources.cores),
+        ),
+        false => None,
+      };
+      spec.cgroup = cgroup.as_ref().map(|cgroup| cgroup.path.clone());
+
+      if self.options.pin_steps && !resources.core_ids.is_empty() {
+        // a core id is repeated when the pool has more cores than the location can use
+        let mut cores = resources.core_ids.clone();
+        cores.sort();
+        cores.dedup();
+        spec.affinity = Some(cores);
+      }
+
+      // each attempt is a separate task
+      let task = self.amdahline.begin_task(&location, &step_display_name);
+      self.amdahline.annotate_task(&location, task, "attempt", &attempt.to_string());
+      self.amdahline.annotate_task(&location, task, "cores", &resources.cores.to_string());
+      self.amdahline.annotate_task(&location, task, "memory", &resources.memory.to_string());
+      if let Some(affinity) = &spec.affinity {
+        self.amdahline.annotate_task(&location, task, "affinity", &affinity::format_cores(affinity));
       }
-      _ => {
-        let status = utils::execute_command(&cmd, &arguments, &step_workdir).await;
+
+      // the step is running (and pending for the watchdog) until the command exits, also if it panics
+      let output = {
+        let _running = self.track(Operation::Step { name: step_name.clone() });
+        let _step = self.start_step();
+
+        utils::execute_command(&spec, self.options.step_log_cap, capture_stdout).await
+      };
+
+      let resources_memory = resources.memory;
+
+      drop(cgroup);
+      drop(resources);
+
+      println!(
+        "{} Completed step: {} with status: {}",
+        debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+        step_display_name,
+        output.status,
+      );
+
+      // record where the logs of the step are
+      for log in [&output.stdout_log, &output.stderr_log] {
+        if log.truncated {
+          println!(
+            "{} Log {:?} truncated to {} of {}",
+            debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+            log.path,
+            utils::format_bytes(self.options.step_log_cap as usize),
+            utils::format_bytes(log.size as usize),
+          );
+        }
+      }
+      self.amdahline.annotate_task(&location, task, "stdout", &format!("{}", output.stdout_log.path.display()));
+      self.amdahline.annotate_task(&location, task, "stderr", &format!("{}", output.stderr_log.path.display()));
+
+      let failure = match output.termination {
+        Some(Termination::TimedOut(timeout)) => format!("timed out after {:?}", timeout),
+        Some(Termination::Cancelled) => "was cancelled".to_string(),
+        None if output.oom_killed => {
+          format!("was killed for exceeding its memory limit of {} MiB", resources_memory)
+        }
+        None if cpu_time_exceeded(&output.status) => {
+          "was killed for exceeding its CPU time limit".to_string()
+        }
+        None if !accepted_status(&output.status, &step_options.accepted_exit_codes) => {
+          format!("failed with status: {}", output.status)
+        }
+        None => break (output, task, true),
+      };
+
+      self.amdahline.annotate_task(&location, task, "failure", &failure);
+
+      if attempt < retry.max_attempts && retry.retries(&output) {
+        let delay = retry.delay_after(attempt);
 
         println!(
-          "{} Completed step: {} with status: {}",
+          "{} Step {} {}, retrying in {:?}",
           debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
           step_display_name,
-          status,
+          failure,
+          delay,
         );
-  
-        (None, status)
+        self.amdahline.end_task(&location, task);
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+
+        // the next attempt starts from a clean workdir
+        std::fs::remove_dir_all(&step_workdir).expect("failed to reset step workdir");
+        std::fs::create_dir_all(&step_workdir).expect("failed to reset step workdir");
+        staged = self.stage_inputs(&input_ports, &step_workdir).await;
+
+        continue;
+      }
+
+      // a cancelled step means the workflow is aborting anyway
+      if output.termination != Some(Termination::Cancelled) {
+        match step_options.on_failure {
+          FailurePolicy::Abort => {}
+          FailurePolicy::Skip => {
+            println!(
+              "{} Step {} {}, skipping its outputs (stderr at {:?})",
+              debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+              step_display_name,
+              failure,
+              output.stderr_log.path,
+            );
+            self.amdahline.end_task(&location, task);
+
+            self.skip_outputs(&step_name, &outputs).await;
+            return;
+          }
+          FailurePolicy::Continue => {
+            println!(
+              "{} Step {} {}, continuing (stderr at {:?})",
+              debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+              step_display_name,
+              failure,
+              output.stderr_log.path,
+            );
+
+            break (output, task, false);
+          }
+        }
       }
+
+      // the workflow is aborting, the steps running on every location are killed
+      self.abort().await;
+
+      panic!(
+        "{} Step {} {}\n--- stderr (last {} bytes, full log at {:?}) ---\n{}\n--- stdout (last {} bytes, full log at {:?}) ---\n{}",
+        debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+        step_display_name,
+        failure,
+        output.stderr_log.tail.len(),
+        output.stderr_log.path,
+        output.stderr_log.tail,
+        output.stdout_log.tail.len(),
+        output.stdout_log.path,
+        output.stdout_log.tail,
+      );
     };
   
-    if !status.success() {
-      panic!("Command failed with status: {}", status);
-    }
-  
-    if output_port.is_some() {
-      let port = self
-      .ports
-      .get(&output_port.unwrap())
-      .expect("port not found");
-  
-      match output_type {
-        StepOutput::File(path_regex) => {
-          let path_regex = path_regex.replace("/", "\\");
-  
-          let path_regex = step_workdir.join(path_regex);
-  
-          let path_regex = path_regex
-            .to_str()
-            .expect("failed to convert to string")
-            .to_string();
-  
-          let res = glob::glob(path_regex.as_str()).expect("failed to glob");
-          let res = res
-            .collect::<Result<Vec<_>, _>>()
-            .expect("failed to collect");
-  
-          if res.len() == 0 {
+    let stdout = output.stdout.map(|stdout| {
+      String::from_utf8(stdout).expect("failed to convert output to string")
+    });
+
+    let mut collected = vec![];
+
+    for (output_port, output_type) in outputs {
+      let data = match output_type {
+        StepOutput::File(path_regex) => match collect_glob_output(&step_workdir, &path_regex, &staged) {
+          Some(data) => data,
+          // a failed step continued past may not have written its outputs
+          None if !succeeded => self.missing_output(&step_name, &output_port, "no file matches its glob"),
+          None => {
             let available_files = std::fs::read_dir(&step_workdir)
               .expect("failed to read dir")
               .map(|res| res.unwrap().path())
@@ -183,33 +373,334 @@ impl Swirl {
               path_regex, available_files
             );
           }
-  
-          if res.len() > 1 {
-            panic!("Multiple files found for regex: {}", path_regex);
+        },
+        StepOutput::Stdout(output_type) => {
+          let stdout = stdout.as_ref().expect("failed to get output");
+
+          match output_type.parse(stdout) {
+            Ok(data) => data,
+            Err(_) if !succeeded => self.missing_output(&step_name, &output_port, "its stdout cannot be parsed"),
+            Err(error) => panic!(
+              "{} Failed to parse the output of step {} as {:?}: {} (stdout: {:?})",
+              debug_prelude(&self.orchestra.self_name(), Some(&step_name)),
+              step_display_name,
+              output_type,
+              error,
+              stdout
+            ),
           }
-  
-          let path = res[0]
-            .to_str()
-            .expect("failed to convert to string")
-            .to_string();
-  
-          port.set(PortData::File(path)).await;
-          port.port_ready.notify_waiters();
-        }
-        StepOutput::Stdout => {
-          let stdout = String::from_utf8(output.expect("failed to get output").stdout)
-            .expect("failed to convert output to string");
-  
-          port.set(PortData::String(stdout)).await;
-          port.port_ready.notify_waiters();
         }
-        StepOutput::None => {
-          port.set(PortData::Empty).await;
-          port.port_ready.notify_waiters();
-        }
-      }
+        StepOutput::None => PortData::Empty,
+      };
+
+      collected.push((output_port, data));
     }
+
+    // the outputs of a failed run are never reused
+    if let (Some(key), true) = (&cache_key, succeeded) {
+      self.store_cached(key, &step_workdir, &collected);
+    }
+
+    self.complete_exec(&step_name, collected).await;
   
     self.amdahline.end_task(&location, task);
   }
+
+  /// Waits for the data of `input_ports`, returning the first port holding skipped data.
+  async fn skipped_input(&self, input_ports: &Vec<PortID>) -> Option<PortID> {
+    for input_port in input_ports {
+      let port = self.ports.get(input_port).expect("port not found");
+
+      if port.wait_for_data().await.is_skip() {
+        return Some(input_port.clone());
+      }
+    }
+
+    None
+  }
+
+  /// Waits for the data of the bool port `condition`, returning its value.
+  async fn condition(&self, condition: &PortID) -> bool {
+    let port = self.ports.get(condition).expect("port not found");
+
+    match port.wait_for_data().await {
+      PortData::Bool(value) => value,
+      data => panic!("condition {} is not a bool: {:?}", condition, data),
+    }
+  }
+
+  /// Returns the skipped data for an output that a failed step did not produce.
+  fn missing_output(&self, step_name: &String, output_port: &PortID, reason: &str) -> PortData {
+    println!(
+      "{} Skipping output port {} of the failed step: {}",
+      debug_prelude(&self.orchestra.self_name(), Some(step_name)),
+      output_port,
+      reason,
+    );
+
+    PortData::Skip
+  }
+
+  /// Marks the data of the output ports of a step as skipped.
+  async fn skip_outputs(&self, step_name: &String, outputs: &Vec<(PortID, StepOutput)>) {
+    let skipped = outputs
+      .iter()
+      .map(|(output_port, _)| (output_port.clone(), PortData::Skip))
+      .collect();
+
+    self.complete_exec(step_name, skipped).await;
+  }
+
+  /**
+   * Links the data of `input_ports` into the step workdir, waiting for it to be available.
+   * Returns the paths of the links, which are never part of the outputs of the step.
+   */
+  async fn stage_inputs(&self, input_ports: &Vec<PortID>, step_workdir: &PathBuf) -> Vec<PathBuf> {
+    let mut staged = vec![];
+
+    for input_port in input_ports {
+      let port = self.ports.get(input_port).expect("port not found");
+
+      let data = port.wait_for_data().await;
+
+      stage_into_workdir(&data, step_workdir, &mut staged);
+    }
+
+    staged
+  }
+}
+
+/// Returns true if `status` is 0 or one of `accepted_exit_codes`.
+fn accepted_status(status: &std::process::ExitStatus, accepted_exit_codes: &Vec<i32>) -> bool {
+  match status.code() {
+    Some(code) => code == 0 || accepted_exit_codes.contains(&code),
+    // killed by a signal
+    None => false,
+  }
+}
+
+/// Returns true if the command was killed for exceeding its CPU time rlimit.
+fn cpu_time_exceeded(status: &std::process::ExitStatus) -> bool {
+  #[cfg(unix)] {
+  use std::os::unix::process::ExitStatusExt;
+  return status.signal() == Some(libc::SIGXCPU);
+  }
+
+  #[cfg(not(unix))] {
+  false
+  }
+}
+
+/// Collects the output of a step from the files in the step workdir matching `path_regex`, except the `staged` inputs:
+/// a single match is a file (or directory), multiple matches are a list of files, no match is `None`.
+fn collect_glob_output(step_workdir: &PathBuf, path_regex: &String, staged: &Vec<PathBuf>) -> Option<PortData> {
+  let path_regex = path_regex.replace("/", "\\");
+
+  let path_regex = step_workdir.join(path_regex);
+
+  let path_regex = path_regex
+    .to_str()
+    .expect("failed to convert to string")
+    .to_string();
+
+  let res = glob::glob(path_regex.as_str()).expect("failed to glob");
+  let res = res
+    .collect::<Result<Vec<_>, _>>()
+    .expect("failed to collect");
+
+  // the logs and the inputs of the step are never part of its output
+  let res = res
+    .into_iter()
+    .filter(|path| !path.ends_with(utils::STDOUT_LOG) && !path.ends_with(utils::STDERR_LOG))
+    .filter(|path| !(staged.contains(path) && path.is_symlink()))
+    .collect::<Vec<_>>();
+
+  if res.len() == 0 {
+    return None;
+  }
+
+  if res.len() > 1 {
+    // glob::glob yields the matches in alphabetical order
+    let paths = res
+      .iter()
+      .map(|path| path.to_str().expect("failed to convert to string").to_string())
+      .collect();
+
+    return Some(PortData::Files(paths));
+  }
+
+  let path = res[0]
+    .to_str()
+    .expect("failed to convert to string")
+    .to_string();
+
+  if res[0].is_dir() {
+    Some(PortData::Directory(path))
+  } else {
+    Some(PortData::File(path))
+  }
+}
+
+/// Links the files (and directories) held by `data` into the step workdir, adding the links to `staged`.
+fn stage_into_workdir(data: &PortData, step_workdir: &PathBuf, staged: &mut Vec<PathBuf>) {
+  match data {
+    PortData::File(path) | PortData::Directory(path) => {
+      // link the file (or directory) to the step workdir
+      staged.push(link_into_workdir(path, step_workdir));
+    }
+    PortData::Files(paths) => {
+      unique_file_names(paths);
+
+      for path in paths {
+        staged.push(link_into_workdir(path, step_workdir));
+      }
+    }
+    PortData::List(values) => {
+      for value in values {
+        stage_into_workdir(value, step_workdir, staged);
+      }
+    }
+    PortData::Empty => {
+      panic!("empty data");
+    }
+    _ => {}
+  }
+}
+
+/// Renders `data` as command arguments, files are referenced by name since they are linked to the step workdir.
+pub(super) fn render_argument(data: &PortData, arguments: &mut Vec<String>) {
+  match data {
+    PortData::File(path) | PortData::Directory(path) => {
+      arguments.push(file_name(path));
+    }
+    PortData::Files(paths) => {
+      // each file is a separate argument
+      for path in paths {
+        arguments.push(file_name(path));
+      }
+    }
+    PortData::String(value) | PortData::Json(value) => {
+      arguments.push(value.clone());
+    }
+    PortData::Int(value) => {
+      arguments.push(value.to_string());
+    }
+    PortData::Int64(value) => {
+      arguments.push(value.to_string());
+    }
+    PortData::Float(value) => {
+      arguments.push(value.to_string());
+    }
+    PortData::Bool(value) => {
+      arguments.push(value.to_string());
+    }
+    PortData::List(values) => {
+      // each value is a separate argument
+      for value in values {
+        render_argument(value, arguments);
+      }
+    }
+    PortData::Skip => {
+      panic!("skipped data");
+    }
+    PortData::Empty => {
+      panic!("empty data");
+    }
+  }
+}
+
+fn file_name(path: &String) -> String {
+  PathBuf::from(path)
+    .file_name()
+    .expect("failed to get file name")
+    .to_str()
+    .expect("failed to convert to string")
+    .to_string()
+}
+
+/// Links the file (or directory) at `path` into the step workdir, keeping its name, and returns the path of the link.
+fn link_into_workdir(path: &String, step_workdir: &PathBuf) -> PathBuf {
+  let new_path = step_workdir.join(file_name(path));
+
+  // the link of a previous run of the workflow is replaced
+  if let Ok(metadata) = std::fs::symlink_metadata(&new_path) {
+    if metadata.is_dir() {
+      std::fs::remove_dir_all(&new_path).expect("failed to remove previous input");
+    } else {
+      std::fs::remove_file(&new_path).expect("failed to remove previous input");
+    }
+  }
+
+  // create symlink
+  #[cfg(unix)]
+  {
+    std::os::unix::fs::symlink(path, &new_path).expect("failed to create symlink");
+  }
+
+  new_path
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("swirl_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir.canonicalize().expect("failed to canonicalize temp dir")
+  }
+
+  #[test]
+  fn wildcard_output_excludes_inputs() {
+    let dir = temp_dir();
+    let inputs = dir.join("inputs");
+    let step_workdir = dir.join("step");
+    std::fs::create_dir_all(&inputs).unwrap();
+    std::fs::create_dir_all(&step_workdir).unwrap();
+
+    let input = inputs.join("input.txt");
+    std::fs::write(&input, "input").unwrap();
+
+    let mut staged = vec![];
+    stage_into_workdir(&PortData::File(input.to_str().unwrap().to_string()), &step_workdir, &mut staged);
+    assert_eq!(staged, vec![step_workdir.join("input.txt")]);
+
+    std::fs::write(step_workdir.join("output.txt"), "output").unwrap();
+    std::fs::write(step_workdir.join(utils::STDOUT_LOG), "log").unwrap();
+
+    let output = collect_glob_output(&step_workdir, &"*".to_string(), &staged);
+    let expected = step_workdir.join("output.txt").to_str().unwrap().to_string();
+    assert_eq!(output, Some(PortData::File(expected)));
+
+    std::fs::write(step_workdir.join("other.txt"), "other").unwrap();
+
+    match collect_glob_output(&step_workdir, &"*".to_string(), &staged) {
+      Some(PortData::Files(paths)) => assert_eq!(paths.len(), 2),
+      output => panic!("unexpected output: {:?}", output),
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn input_replaced_by_step_is_an_output() {
+    let dir = temp_dir();
+    let input = dir.join("input.txt");
+    let step_workdir = dir.join("step");
+    std::fs::create_dir_all(&step_workdir).unwrap();
+    std::fs::write(&input, "input").unwrap();
+
+    let mut staged = vec![];
+    stage_into_workdir(&PortData::File(input.to_str().unwrap().to_string()), &step_workdir, &mut staged);
+
+    // the step writes a new file in place of the link
+    std::fs::remove_file(step_workdir.join("input.txt")).unwrap();
+    std::fs::write(step_workdir.join("input.txt"), "output").unwrap();
+
+    let output = collect_glob_output(&step_workdir, &"*.txt".to_string(), &staged);
+    let expected = step_workdir.join("input.txt").to_str().unwrap().to_string();
+    assert_eq!(output, Some(PortData::File(expected)));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
 }
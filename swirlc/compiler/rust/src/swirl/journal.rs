@@ -0,0 +1,350 @@
+use std::{collections::HashMap, io::{BufRead, Seek, Write}, path::PathBuf, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::orchestra::{receive::PartialReceive, utils::debug_prelude};
+
+use super::{sequence::message_id, PortData, PortID, Swirl};
+
+/// Name of the journal file in the workdir of a location.
+pub const JOURNAL_FILE: &str = "journal.jsonl";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// JournalEntry is an operation completed by a location.
+pub enum JournalEntry {
+  /// The step ran (or was skipped), setting the data of its output ports
+  Exec { step: String, outputs: Vec<(PortID, PortData)> },
+  /// The `seq`-th value of the port was received from the sender
+  Receive {
+    port: PortID,
+    #[serde(default)]
+    seq: u64,
+    sender: String,
+    data: PortData,
+  },
+  /// The branch of the choice was taken
+  Choice { choice: usize, branch: usize },
+}
+
+impl JournalEntry {
+  /// Returns the key identifying the operation, the same in every run of the workflow.
+  pub fn key(&self) -> String {
+    match self {
+      JournalEntry::Exec { step, .. } => exec_key(step),
+      JournalEntry::Receive { port, seq, sender, .. } => receive_key(port, *seq, sender),
+      JournalEntry::Choice { choice, .. } => choice_key(*choice),
+    }
+  }
+}
+
+fn exec_key(step: &String) -> String {
+  format!("exec {}", step)
+}
+
+fn receive_key(port: &PortID, seq: u64, sender: &String) -> String {
+  format!("receive {} from {}", message_id(port, seq), sender)
+}
+
+fn choice_key(choice: usize) -> String {
+  format!("choice {}", choice)
+}
+
+/**
+ * Journal is the append-only log of the operations completed by a location, one JSON entry per line.
+ * Each entry is synced to disk before the operation is visible to the rest of the workflow,
+ * so that an interrupted run can be resumed from the operations it completed.
+ */
+pub struct Journal {
+  /// None if the operations are not recorded (e.g. in a dry run)
+  file: Option<Mutex<std::fs::File>>,
+  /// operations completed by the previous runs, by key
+  completed: HashMap<String, JournalEntry>,
+}
+
+impl Journal {
+  /**
+   * Opens the journal at `path`. When resuming, the completed operations are loaded from it,
+   * otherwise it is cleared.
+   */
+  pub fn open(path: PathBuf, resume: bool) -> Self {
+    let mut file = std::fs::File::options()
+      .create(true)
+      .read(true)
+      .append(true)
+      .open(&path)
+      .expect(format!("failed to open journal: {:?}", path).as_str());
+
+    let mut completed = HashMap::new();
+
+    if resume {
+      let mut valid_length = 0;
+      let mut reader = std::io::BufReader::new(&file);
+
+      loop {
+        let mut line = vec![];
+        let read = reader.read_until(b'\n', &mut line).expect("failed to read journal");
+
+        // the last entry is torn if the run was interrupted while writing it, also if it lacks only the newline
+        if line.pop() != Some(b'\n') {
+          break;
+        }
+
+        let entry: JournalEntry = match serde_json::from_slice(&line) {
+          Ok(entry) => entry,
+          Err(_) => break,
+        };
+
+        valid_length += read as u64;
+        completed.insert(entry.key(), entry);
+      }
+
+      file.set_len(valid_length).expect("failed to truncate journal");
+    } else {
+      file.set_len(0).expect("failed to clear journal");
+    }
+
+    file.seek(std::io::SeekFrom::End(0)).expect("failed to seek journal");
+
+    Journal {
+      file: Some(Mutex::new(file)),
+      completed,
+    }
+  }
+
+  /// Returns a journal that records nothing.
+  pub fn disabled() -> Self {
+    Journal {
+      file: None,
+      completed: HashMap::new(),
+    }
+  }
+
+  /// Returns the number of operations completed by the previous runs.
+  pub fn completed_count(&self) -> usize {
+    self.completed.len()
+  }
+
+  /// Returns the entry of the operation `key`, if completed by a previous run.
+  pub fn completed(&self, key: &String) -> Option<&JournalEntry> {
+    self.completed.get(key)
+  }
+
+  /// Appends `entry` to the journal and syncs it to disk, unless completed by a previous run.
+  pub fn record(&self, entry: &JournalEntry) {
+    let file = match &self.file {
+      Some(file) => file,
+      None => return,
+    };
+
+    if self.completed.contains_key(&entry.key()) {
+      return;
+    }
+
+    let mut line = serde_json::to_vec(entry).expect("failed to serialize journal entry");
+    line.push(b'\n');
+
+    let mut file = file.lock().expect("failed to lock journal");
+    file.write_all(&line).expect("failed to write journal");
+    file.sync_data().expect("failed to sync journal");
+  }
+}
+
+impl Swirl {
+  /**
+   * Sets the output ports of `step` as recorded in the journal, if it completed in a previous run.
+   * Returns true if the step completed.
+   */
+  pub(super) async fn resume_exec(&self, step: &String) -> bool {
+    let outputs = match self.journal.completed(&exec_key(step)) {
+      Some(JournalEntry::Exec { outputs, .. }) => outputs.clone(),
+      _ => return false,
+    };
+
+    println!(
+      "{} Step {} completed before the interruption, restoring its outputs",
+      debug_prelude(&self.orchestra.self_name(), Some(step)),
+      step,
+    );
+
+    for (output_port, data) in outputs {
+      self.init_port(output_port, data).await;
+    }
+
+    true
+  }
+
+  /// Records that `step` completed with `outputs`, then sets its output ports.
+  pub(super) async fn complete_exec(&self, step: &String, outputs: Vec<(PortID, PortData)>) {
+    self.journal.record(&JournalEntry::Exec {
+      step: step.clone(),
+      outputs: outputs.clone(),
+    });
+
+    for (output_port, data) in outputs {
+      self.init_port(output_port, data).await;
+    }
+  }
+
+  /**
+   * Returns the `seq`-th value of the port `port_id` received from `sender`, if received in a previous run.
+   * Sends are replayed when resuming, since the destination might not have received the data
+   * before the interruption: the replayed data of the receives in the journal is discarded.
+   */
+  pub(super) fn resume_receive(&self, port_id: &PortID, seq: u64, sender: &String) -> Option<PortData> {
+    match self.journal.completed(&receive_key(port_id, seq, sender)) {
+      Some(JournalEntry::Receive { data, .. }) => Some(data.clone()),
+      _ => None,
+    }
+  }
+
+  /**
+   * Records that the `seq`-th value of the port `port_id` was received from `sender`, then sets the port,
+   * after the previous values received from `sender`.
+   */
+  pub(super) async fn complete_receive(&self, port_id: &PortID, seq: u64, sender: &String, data: PortData) {
+    self
+      .sequences
+      .deliver(port_id, sender, seq, || {
+        self.journal.record(&JournalEntry::Receive {
+          port: port_id.clone(),
+          seq,
+          sender: sender.clone(),
+          data: data.clone(),
+        });
+
+        self.ports.get(port_id).expect("port not found").set(data);
+        self.progress();
+      })
+      .await;
+  }
+
+  /// Returns the branch taken by the choice `choice`, if taken in a previous run.
+  pub(super) fn resume_choice(&self, choice: usize) -> Option<usize> {
+    match self.journal.completed(&choice_key(choice)) {
+      Some(JournalEntry::Choice { branch, .. }) => Some(*branch),
+      _ => None,
+    }
+  }
+
+  /// Records that the choice `choice` took `branch`, before any of its operations runs.
+  pub(super) fn complete_choice(&self, choice: usize, branch: usize) {
+    self.journal.record(&JournalEntry::Choice { choice, branch });
+  }
+
+  /// Reads and drops the data of `received`, replayed by a sender for a receive in the journal.
+  pub(super) async fn discard(&self, received: PartialReceive) {
+    if received.header.transfer_id.is_none() {
+      received.collect_blocking_into(tokio::io::sink()).await;
+      return;
+    }
+
+    // file transfers are reassembled on disk, the files already stored are only linked
+    let path = self.workdir.join(format!("discarded_{}", uuid::Uuid::new_v4()));
+    received.collect_blocking_file_transfer(path.clone(), self.store_dir()).await;
+
+    let _ = std::fs::remove_file(&path);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn journal_path() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("swirl_test_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir.join(JOURNAL_FILE)
+  }
+
+  fn exec(step: &str) -> JournalEntry {
+    JournalEntry::Exec { step: step.to_string(), outputs: vec![("p1".into(), PortData::Int(1))] }
+  }
+
+  fn append(path: &PathBuf, bytes: &[u8]) {
+    std::fs::OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+  }
+
+  #[test]
+  fn completed_operations_are_replayed() {
+    let path = journal_path();
+
+    let journal = Journal::open(path.clone(), false);
+    journal.record(&exec("s1"));
+    journal.record(&JournalEntry::Receive { port: "p2".into(), seq: 1, sender: "l1".into(), data: PortData::Bool(true) });
+    journal.record(&JournalEntry::Choice { choice: 0, branch: 2 });
+    drop(journal);
+
+    let journal = Journal::open(path.clone(), true);
+    assert_eq!(journal.completed_count(), 3);
+    assert!(journal.completed(&exec_key(&"s1".to_string())).is_some());
+    assert!(journal.completed(&receive_key(&"p2".into(), 1, &"l1".to_string())).is_some());
+    assert!(journal.completed(&receive_key(&"p2".into(), 0, &"l1".to_string())).is_none());
+    assert!(matches!(journal.completed(&choice_key(0)), Some(JournalEntry::Choice { branch: 2, .. })));
+
+    // the operations completed before are not recorded again
+    let length = std::fs::metadata(&path).unwrap().len();
+    journal.record(&exec("s1"));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), length);
+
+    // a run that does not resume starts from an empty journal
+    drop(journal);
+    let journal = Journal::open(path.clone(), false);
+    assert_eq!(journal.completed_count(), 0);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn torn_entry_is_truncated() {
+    let path = journal_path();
+
+    let journal = Journal::open(path.clone(), false);
+    journal.record(&exec("s1"));
+    drop(journal);
+    let length = std::fs::metadata(&path).unwrap().len();
+
+    append(&path, b"{\"Exec\":{\"step\":\"s2\",\"outp");
+
+    let journal = Journal::open(path.clone(), true);
+    assert_eq!(journal.completed_count(), 1);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), length);
+
+    // the entries recorded after the truncation are read by the next resume
+    journal.record(&exec("s3"));
+    drop(journal);
+
+    let journal = Journal::open(path.clone(), true);
+    assert_eq!(journal.completed_count(), 2);
+    assert!(journal.completed(&exec_key(&"s3".to_string())).is_some());
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn entry_without_newline_is_truncated() {
+    let path = journal_path();
+
+    let journal = Journal::open(path.clone(), false);
+    journal.record(&exec("s1"));
+    drop(journal);
+    let length = std::fs::metadata(&path).unwrap().len();
+
+    // the run was interrupted after writing the entry, before its newline
+    append(&path, &serde_json::to_vec(&exec("s2")).unwrap());
+
+    let journal = Journal::open(path.clone(), true);
+    assert_eq!(journal.completed_count(), 1);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), length);
+
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn disabled_journal_records_nothing() {
+    let journal = Journal::disabled();
+    journal.record(&exec("s1"));
+
+    assert_eq!(journal.completed_count(), 0);
+  }
+}
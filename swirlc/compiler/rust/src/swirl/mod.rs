@@ -2,19 +2,45 @@ pub mod send;
 pub mod receive;
 pub mod broadcast;
 pub mod exec;
+pub mod cache;
+pub mod choice;
+pub mod journal;
+pub mod plan;
 pub mod config;
+pub mod options;
+pub mod resources;
+pub mod sequence;
+pub mod watchdog;
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::{atomic::AtomicUsize, Arc, Mutex}, time::Duration};
 use config::PORTS;
+use journal::Journal;
+use options::SwirlOptions;
+use resources::ResourcePool;
+use sequence::Sequences;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Notify, RwLock};
+use tokio::sync::{watch, RwLock};
+use watchdog::Activity;
 
-use crate::{amdahline::Amdahline, orchestra::{LocationInfo, Orchestra}};
+use crate::{amdahline::Amdahline, orchestra::{affinity, utils::{debug_prelude, file_content_hash, CommandOutput, ResourceLimits, Termination}, LocationInfo, Orchestra}};
 
 // TODO: port id should be an enum
 pub type PortID = String;
 
-#[derive(Debug)]
+/// Maximum number of connections a location opens at the same time to send data.
+pub const MAX_CONNECTIONS: usize = 128;
+
+/// FileVersion is the modification time and the size of a file, which change when the file is rewritten.
+type FileVersion = (std::time::SystemTime, u64);
+
+/// Returns the version of the file at `path`.
+fn file_version(path: &String) -> FileVersion {
+  let metadata = std::fs::metadata(path).expect(format!("failed to read metadata of file: {:?}", path).as_str());
+
+  (metadata.modified().expect("failed to read modification time"), metadata.len())
+}
+
+#[derive(Debug, Clone)]
 /// StepArgument is an enum that represents the argument of a step command.
 pub enum StepArgument {
   /// The argument is the value from the given port
@@ -40,55 +66,382 @@ impl From<&str> for StepArgument {
 pub enum StepOutput {
   /// The step is expected to write the output to a file at the given path
   File(String),
-  /// The step is expected to write the output to the standard output
-  Stdout,
+  /// The step is expected to write the output to the standard output, parsed as the given type
+  Stdout(OutputType),
   None,
 }
 
-#[derive(Eq, PartialEq, Hash, Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, Default)]
+/// StepOptions holds the execution settings of a step.
+pub struct StepOptions {
+  /// Run the command line through the shell (`sh -c`), so that redirections and pipes work.
+  /// Values read from ports are quoted, literal arguments are passed verbatim.
+  pub shell: bool,
+  /// Variables added to the environment of the command
+  pub env: HashMap<String, String>,
+  /// The standard input of the command: the content of a file port, the value of
+  /// any other port or a literal string. The standard input is inherited if not set.
+  pub stdin: Option<StepArgument>,
+  /// Time after which the command is killed, the `--step-timeout` option if not set
+  pub timeout: Option<std::time::Duration>,
+  /// How the command is run again when it fails
+  pub retry: RetryPolicy,
+  /// Cores reserved for the command while it runs (at least 1)
+  pub cores: u32,
+  /// Memory (in MiB) reserved for the command while it runs
+  pub memory: u64,
+  /// Resource limits of the command processes
+  pub limits: ResourceLimits,
+  /// Exit codes of a successful run besides 0
+  pub accepted_exit_codes: Vec<i32>,
+  /// What happens when the command fails (after the retries)
+  pub on_failure: FailurePolicy,
+  /// Bool port the step runs on: the step is skipped if it holds false, so are the steps reading its outputs
+  pub when: Option<PortID>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// FailurePolicy is what happens when the command of a step fails.
+pub enum FailurePolicy {
+  /// The steps running on the location are killed and the location panics
+  #[default]
+  Abort,
+  /// The outputs of the step are skipped, so are the steps reading them
+  Skip,
+  /// The outputs of the step are collected as if the command succeeded, the missing ones are skipped
+  Continue,
+}
+
+#[derive(Debug, Clone)]
+/// RetryPolicy describes how many times a failing step is run again.
+pub struct RetryPolicy {
+  /// Number of runs of the command, including the first one
+  pub max_attempts: u32,
+  /// Time to wait before the second attempt
+  pub delay: std::time::Duration,
+  /// Factor applied to the delay after each attempt
+  pub backoff: f64,
+  /// Exit codes of the command that are retried, any failure is retried if empty
+  pub retry_on: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 1,
+      delay: std::time::Duration::from_secs(1),
+      backoff: 2.0,
+      retry_on: vec![],
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Returns the time to wait after the failed attempt `attempt` (the first is 1) before running the command again.
+  pub fn delay_after(&self, attempt: u32) -> std::time::Duration {
+    self.delay.mul_f64(self.backoff.powi(attempt as i32 - 1))
+  }
+
+  /// Returns true if the failed command of `output` should be run again.
+  pub fn retries(&self, output: &CommandOutput) -> bool {
+    match output.termination {
+      Some(Termination::Cancelled) => false,
+      Some(Termination::TimedOut(_)) => self.retry_on.is_empty(),
+      None => match output.status.code() {
+        Some(code) => self.retry_on.is_empty() || self.retry_on.contains(&code),
+        // killed by a signal
+        None => self.retry_on.is_empty(),
+      },
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// OutputType is the type of the value a step writes to the standard output.
+pub enum OutputType {
+  String,
+  Int,
+  Int64,
+  Float,
+  Bool,
+  Json,
+}
+
+impl OutputType {
+  /// Parses the standard output of a step into the port data of this type.
+  /// Apart from `String`, surrounding whitespace is ignored.
+  pub fn parse(&self, stdout: &str) -> Result<PortData, String> {
+    let value = stdout.trim();
+
+    match self {
+      OutputType::String => Ok(PortData::String(stdout.to_string())),
+      OutputType::Int => value.parse().map(PortData::Int).map_err(|e| e.to_string()),
+      OutputType::Int64 => value.parse().map(PortData::Int64).map_err(|e| e.to_string()),
+      OutputType::Float => value.parse().map(PortData::Float).map_err(|e| e.to_string()),
+      OutputType::Bool => match value.to_lowercase().as_str() {
+        "true" => Ok(PortData::Bool(true)),
+        "false" => Ok(PortData::Bool(false)),
+        _ => Err("expected `true` or `false`".to_string()),
+      },
+      OutputType::Json => serde_json::from_str::<serde_json::Value>(value)
+        .map(|_| PortData::Json(value.to_string()))
+        .map_err(|e| e.to_string()),
+    }
+  }
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
 pub enum PortData {
   File(String),
+  Directory(String),
+  Files(Vec<String>),
   String(String),
   Int(i32),
+  Int64(i64),
+  Float(f64),
   Bool(bool),
+  /// A list of values, each rendered as a separate step argument
+  List(Vec<PortData>),
+  /// A JSON document, stored as its serialized text
+  Json(String),
+  /// The step producing the data failed and was skipped, so are the steps reading it
+  Skip,
   Empty,
 }
 
 impl PortData {
+  pub fn is_skip(&self) -> bool {
+    matches!(self, PortData::Skip)
+  }
+
   pub fn is_empty(&self) -> bool {
     match self {
       PortData::Empty => true,
       _ => false,
     }
   }
+
+  /// For data transferred as an archive (directories and batches of files), returns the paths
+  /// to archive and the names of their entries, in order.
+  pub fn archive_entries(&self) -> Option<(Vec<PathBuf>, Vec<String>)> {
+    match self {
+      PortData::Directory(path) => {
+        let path = PathBuf::from(path);
+        let name = file_name(&path);
+
+        Some((vec![path], vec![name]))
+      }
+      PortData::Files(paths) => {
+        let names = unique_file_names(paths);
+
+        Some((paths.iter().map(PathBuf::from).collect(), names))
+      }
+      _ => None,
+    }
+  }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// PortHeader describes the data of a port sent in a message, attached to the message header
+/// (at most `MESSAGE_HEADER_SIZE` bytes): large data is in the message body.
+pub enum PortHeader {
+  /// A file with the given name, the body is its content
+  File(String),
+  /// A directory, the body is its manifest followed by its archive
+  Directory,
+  /// A batch of files, the body is their manifest followed by their archive
+  Files,
+  /// Any other data, the body is its serialization
+  Value,
+}
+
+/**
+ * Returns the manifest of an archive: the names of its entries, prefixed by the length of their serialization.
+ * The manifest is sent before the archive, so that the names of many files do not overflow the message header.
+ */
+pub fn encode_manifest(names: &Vec<String>) -> Vec<u8> {
+  let names = bincode::serialize(names).unwrap();
+
+  let mut manifest = (names.len() as u64).to_be_bytes().to_vec();
+  manifest.extend(names);
+
+  manifest
+}
+
+/**
+ * Returns the names of the files at `paths`.
+ * Panics if two files have the same name, since they would replace each other in the step workdir
+ * or when extracted at the destination.
+ */
+pub fn unique_file_names(paths: &Vec<String>) -> Vec<String> {
+  let mut seen: HashMap<String, &String> = HashMap::new();
+
+  paths
+    .iter()
+    .map(|path| {
+      let name = file_name(&PathBuf::from(path));
+
+      if let Some(other) = seen.insert(name.clone(), path) {
+        panic!("files {} and {} have the same name {}", other, path, name);
+      }
+
+      name
+    })
+    .collect()
+}
+
+fn file_name(path: &PathBuf) -> String {
+  path
+    .file_name()
+    .expect("failed to get file name")
+    .to_str()
+    .expect("failed to convert to string")
+    .to_string()
+}
+
+#[derive(Debug, Clone)]
+/// PortValue is the data held by a port, with the version of the write that set it.
+pub struct PortValue {
+  /// incremented by every write, 0 before the first one
+  pub version: u64,
+  pub data: PortData,
+}
+
+/**
+ * Port holds the data of a port on a watch channel: a waiter checks the current value
+ * and waits for the next writes atomically, so that it never misses one.
+ * A port carries many values one after the other: a receive empties it until its value arrives,
+ * so that the operations following the receive wait for the new value instead of reading the previous one.
+ */
 pub struct Port {
-  pub port_ready: Notify,
-  pub value: RwLock<PortData>,
+  value: watch::Sender<PortValue>,
 }
 
 impl Port {
-  pub async fn set(&self, value: PortData) {
-    *self.value.write().await = value;
-  }
+  pub fn new() -> Self {
+    let value = PortValue {
+      version: 0,
+      data: PortData::Empty,
+    };
 
-  pub async fn wait_for_data(&self) {
-    if self.value.read().await.is_empty() {
-      self.port_ready.notified().await;
+    Port {
+      value: watch::channel(value).0,
     }
+  }
 
-    if self.value.read().await.is_empty() {
-      panic!("Port is empty, this should NOT happen");
-    }
+  /// Sets the data of the port and wakes up its waiters, returning the version of the write.
+  pub fn set(&self, data: PortData) -> u64 {
+    let mut version = 0;
+
+    self.value.send_modify(|value| {
+      value.version += 1;
+      value.data = data;
+      version = value.version;
+    });
+
+    version
+  }
+
+  /// Returns the current value of the port, which might be empty.
+  pub fn get(&self) -> PortValue {
+    self.value.borrow().clone()
+  }
+
+  /// Returns the number of tasks waiting for the port.
+  pub fn waiters(&self) -> usize {
+    self.value.receiver_count()
+  }
+
+  /**
+   * Returns the data of the port.
+   * BLOCKING: waits for the port to hold data.
+   */
+  pub async fn wait_for_data(&self) -> PortData {
+    let mut receiver = self.value.subscribe();
+
+    let value = receiver
+      .wait_for(|value| !value.data.is_empty())
+      .await
+      .expect("port closed")
+      .clone();
+
+    value.data
   }
 }
 
+/// RunningStep is a step running on the location, counted by `cancel_steps` until dropped.
+pub(super) struct RunningStep {
+  running_steps: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for RunningStep {
+  fn drop(&mut self) {
+    self.running_steps.send_modify(|running| *running -= 1);
+  }
+}
+
+/// Sets `steps_cancelled`, killing the running steps and the ones started afterwards, and waits for the running steps to exit.
+async fn cancel_steps(steps_cancelled: &watch::Sender<bool>, running_steps: &watch::Sender<usize>) {
+  steps_cancelled.send_replace(true);
+
+  running_steps
+    .subscribe()
+    .wait_for(|running| *running == 0)
+    .await
+    .expect("failed to wait for the running steps");
+}
+
+/**
+ * Spawns a task that, when another location aborts the workflow, kills the steps running on this location
+ * and exits with an error, since the data this location waits for will never be sent.
+ */
+fn spawn_abort_listener(
+  orchestra: Arc<Orchestra>,
+  steps_cancelled: Arc<watch::Sender<bool>>,
+  running_steps: Arc<watch::Sender<usize>>,
+) {
+  tokio::spawn(async move {
+    let mut aborted = orchestra.aborted();
+
+    let origin = match aborted.wait_for(|origin| origin.is_some()).await {
+      Ok(origin) => origin.expect("failed to get aborting location"),
+      Err(_) => return,
+    };
+
+    println!(
+      "{} Aborting, the workflow was aborted by location {}",
+      debug_prelude(&orchestra.self_name(), None),
+      orchestra.location_name(origin),
+    );
+
+    cancel_steps(&steps_cancelled, &running_steps).await;
+
+    std::process::exit(1);
+  });
+}
+
 pub struct Swirl {
   ports: Arc<HashMap<PortID, Port>>,
   orchestra: Arc<Orchestra>,
   workdir: PathBuf,
   connection_limit: Arc<tokio::sync::Semaphore>,
+  /// cores and memory available to the steps
+  resources: Arc<ResourcePool>,
+  options: SwirlOptions,
+  /// content hashes of the sent files, by path, with the version of the file they were computed on
+  content_hashes: Arc<RwLock<HashMap<String, (FileVersion, String)>>>,
+  /// set to true to kill the running steps
+  steps_cancelled: Arc<watch::Sender<bool>>,
+  /// number of step commands currently running
+  running_steps: Arc<watch::Sender<usize>>,
+  /// operations completed by this location, in this run and in the resumed ones
+  journal: Arc<Journal>,
+  /// depth of the group whose plan is printed in a dry run
+  plan_depth: AtomicUsize,
+  /// pending operations and last progress, reported by the watchdog
+  activity: Arc<Mutex<Activity>>,
+  /// numbers of the values of the ports sent to and received from the other locations
+  sequences: Sequences,
   pub amdahline: Arc<Amdahline>
 }
 
@@ -97,6 +450,7 @@ impl Swirl {
     location: String,
     address_map: HashMap<String, LocationInfo>,
     workdir: PathBuf,
+    options: SwirlOptions,
   ) -> Self {
     let mut ports = HashMap::new();
 
@@ -104,29 +458,301 @@ impl Swirl {
     for port in PORTS {
       ports.insert(
         port.to_string(),
-        Port {
-          port_ready: Notify::new(),
-          value: RwLock::new(PortData::Empty),
-        },
+        Port::new(),
       );
     }
 
+    // the locations running on the same node, in the same order on every location
+    let machine = &address_map.get(&location).expect("location not found").machine;
+    let mut colocated = address_map
+      .iter()
+      .filter(|(_, info)| &info.machine == machine)
+      .map(|(name, _)| name.clone())
+      .collect::<Vec<_>>();
+    colocated.sort();
+
     let orchestra = Arc::new(Orchestra::new(location.clone(), address_map));
 
-    orchestra.accept_connections();
+    // a dry run does not touch the network, nor the logs and the workdir of the real runs
+    if !options.dry_run {
+      orchestra.accept_connections();
+    }
+
+    let amdahline = match options.dry_run {
+      true => Arc::new(Amdahline::new(format!("amdahline/{}.dry-run.log", location))),
+      false => Arc::new(Amdahline::new(format!("amdahline/{}.log", location))),
+    };
+
+    // pin before creating the resource pool, which is sized from the usable cores
+    if options.pin_locations && !options.dry_run {
+      let index = colocated.iter().position(|name| name == &location).expect("location not found");
+      let cores = affinity::location_share(&affinity::current_cores(), index, colocated.len());
+
+      match affinity::pin_process(&cores) {
+        Ok(()) => {
+          println!(
+            "{} Pinned to cores {} ({} of {} locations on the node)",
+            debug_prelude(&orchestra.self_name(), None),
+            affinity::format_cores(&cores),
+            index + 1,
+            colocated.len()
+          );
+          amdahline.annotate_executor(&location, "affinity", &affinity::format_cores(&cores));
+        }
+        Err(error) => {
+          println!("{} Failed to pin the location: {}", debug_prelude(&orchestra.self_name(), None), error);
+        }
+      }
+    }
+
+    let resources = Arc::new(ResourcePool::new(options.cores, options.memory));
+
+    println!(
+      "{} Resources for the steps: {} cores, {} MiB of memory",
+      debug_prelude(&orchestra.self_name(), None),
+      resources.cores,
+      resources.memory
+    );
+
+    let journal = match options.dry_run {
+      true => Arc::new(Journal::disabled()),
+      false => {
+        std::fs::create_dir_all(&workdir).expect(format!("failed to create workdir {:?}", workdir).as_str());
+        Arc::new(Journal::open(workdir.join(journal::JOURNAL_FILE), options.resume))
+      }
+    };
+
+    if options.dry_run {
+      println!(
+        "[{}] plan: (dry run, the operations between two \"then\" run concurrently)",
+        orchestra.self_name()
+      );
+    } else if options.resume {
+      println!(
+        "{} Resuming: {} operations completed before the interruption",
+        debug_prelude(&orchestra.self_name(), None),
+        journal.completed_count()
+      );
+    }
+
+    let ports = Arc::new(ports);
+    let activity = Arc::new(Mutex::new(Activity::new()));
+    let steps_cancelled = Arc::new(watch::channel(false).0);
+    let running_steps = Arc::new(watch::channel(0).0);
+
+    if !options.dry_run {
+      spawn_abort_listener(orchestra.clone(), steps_cancelled.clone(), running_steps.clone());
+    }
+
+    if options.stall_timeout > 0.0 && !options.dry_run {
+      watchdog::spawn_watchdog(
+        location.clone(),
+        Duration::from_secs_f64(options.stall_timeout),
+        ports.clone(),
+        activity.clone(),
+        amdahline.clone(),
+      );
+    }
 
     Swirl {
       orchestra,
-      ports: Arc::new(ports),
+      ports,
       workdir,
-      connection_limit: Arc::new(tokio::sync::Semaphore::new(128)),
-      amdahline: Arc::new(Amdahline::new(format!("amdahline/{}.log", location))),
+      connection_limit: Arc::new(tokio::sync::Semaphore::new(MAX_CONNECTIONS)),
+      resources,
+      options,
+      content_hashes: Arc::new(RwLock::new(HashMap::new())),
+      steps_cancelled,
+      running_steps,
+      journal,
+      plan_depth: AtomicUsize::new(0),
+      activity,
+      sequences: Sequences::default(),
+      amdahline,
     }
   }
 
+  /// Kills the steps running on this location and the ones started afterwards,
+  /// used when the workflow is aborting. BLOCKING: waits for the running steps to exit.
+  pub async fn cancel_steps(&self) {
+    cancel_steps(&self.steps_cancelled, &self.running_steps).await;
+  }
+
+  /**
+   * Aborts the workflow: tells the other locations, which kill their steps and exit,
+   * and kills the steps running on this location. BLOCKING: waits for the running steps to exit.
+   */
+  pub async fn abort(&self) {
+    self.orchestra.abort_locations().await;
+
+    self.cancel_steps().await;
+  }
+
+  /// Counts a step as running on this location until the returned value is dropped, also when the step panics.
+  pub(super) fn start_step(&self) -> RunningStep {
+    self.running_steps.send_modify(|running| *running += 1);
+
+    RunningStep {
+      running_steps: self.running_steps.clone(),
+    }
+  }
+
+  /**
+   * Returns the content hash of the file at `path`, computing it on first use of each version of the file,
+   * so that a file rewritten in place (e.g. by a retried step) is hashed again.
+   */
+  pub async fn content_hash(&self, path: &String) -> String {
+    let version = file_version(path);
+
+    if let Some((hashed_version, hash)) = self.content_hashes.read().await.get(path) {
+      if *hashed_version == version {
+        return hash.clone();
+      }
+    }
+
+    let hash = file_content_hash(PathBuf::from(path)).await;
+    self.content_hashes.write().await.insert(path.clone(), (version, hash.clone()));
+
+    hash
+  }
+
+  /// Directory of the content-addressed copies of the received files.
+  pub fn store_dir(&self) -> PathBuf {
+    self.workdir.join("store")
+  }
+
   pub async fn init_port(&self, port: PortID, value: PortData) {
-    let data = self.ports.get(&port).expect("port not found");
-    data.set(value).await;
-    data.port_ready.notify_waiters();
+    self.ports.get(&port).expect("port not found").set(value);
+
+    self.progress();
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn port_waits_for_data() {
+    let port = Arc::new(Port::new());
+
+    let waiter = tokio::spawn({
+      let port = port.clone();
+      async move { port.wait_for_data().await }
+    });
+
+    tokio::task::yield_now().await;
+    assert!(!waiter.is_finished());
+
+    assert_eq!(port.set(PortData::Int(1)), 1);
+    assert_eq!(waiter.await.unwrap(), PortData::Int(1));
+    assert_eq!(port.get().version, 1);
+  }
+
+  #[tokio::test]
+  async fn two_receives_on_a_port_are_both_consumed() {
+    let port = Arc::new(Port::new());
+    let sequences = Sequences::default();
+    let sender = "l1".to_string();
+    let port_id: PortID = "p1".into();
+
+    // first receive: the port is empty until the value arrives
+    let first = sequences.next_receive(&port_id, &sender);
+    port.set(PortData::Empty);
+    sequences.deliver(&port_id, &sender, first, || { port.set(PortData::Int(1)); }).await;
+
+    assert_eq!(port.wait_for_data().await, PortData::Int(1));
+
+    // second receive: the step following it waits for the new value, not the previous one
+    let second = sequences.next_receive(&port_id, &sender);
+    port.set(PortData::Empty);
+
+    let consumer = tokio::spawn({
+      let port = port.clone();
+      async move { port.wait_for_data().await }
+    });
+
+    tokio::task::yield_now().await;
+    assert!(!consumer.is_finished());
+
+    sequences.deliver(&port_id, &sender, second, || { port.set(PortData::Int(2)); }).await;
+
+    assert_eq!(consumer.await.unwrap(), PortData::Int(2));
+    assert_eq!((first, second), (0, 1));
+  }
+
+  #[test]
+  fn output_types_are_parsed() {
+    assert_eq!(OutputType::String.parse(" text\n"), Ok(PortData::String(" text\n".to_string())));
+    assert_eq!(OutputType::Int.parse("42\n"), Ok(PortData::Int(42)));
+    assert_eq!(OutputType::Int64.parse(" 8589934592 "), Ok(PortData::Int64(8589934592)));
+    assert_eq!(OutputType::Float.parse("2.5\n"), Ok(PortData::Float(2.5)));
+    assert_eq!(OutputType::Bool.parse("TRUE\n"), Ok(PortData::Bool(true)));
+    assert_eq!(OutputType::Bool.parse("false"), Ok(PortData::Bool(false)));
+    assert_eq!(OutputType::Json.parse("{\"a\": [1]}\n"), Ok(PortData::Json("{\"a\": [1]}".to_string())));
+  }
+
+  #[test]
+  fn invalid_outputs_are_errors() {
+    assert!(OutputType::Int.parse("4.2").is_err());
+    assert!(OutputType::Int.parse("8589934592").is_err());
+    assert!(OutputType::Float.parse("").is_err());
+    assert!(OutputType::Bool.parse("yes").is_err());
+    assert!(OutputType::Json.parse("{").is_err());
+  }
+
+  /// Returns the output of a command that exited with `code`, or was killed if None.
+  fn command_output(code: Option<i32>, termination: Option<Termination>) -> CommandOutput {
+    use std::os::unix::process::ExitStatusExt;
+
+    let log = || crate::orchestra::utils::StreamLog {
+      path: PathBuf::new(),
+      size: 0,
+      truncated: false,
+      tail: String::new(),
+    };
+
+    CommandOutput {
+      status: match code {
+        Some(code) => std::process::ExitStatus::from_raw(code << 8),
+        None => std::process::ExitStatus::from_raw(libc::SIGKILL),
+      },
+      stdout: None,
+      stdout_log: log(),
+      stderr_log: log(),
+      termination,
+      oom_killed: false,
+    }
+  }
+
+  #[test]
+  fn retry_delay_backs_off() {
+    let retry = RetryPolicy {
+      max_attempts: 4,
+      delay: Duration::from_millis(100),
+      backoff: 3.0,
+      retry_on: vec![],
+    };
+
+    assert_eq!(retry.delay_after(1), Duration::from_millis(100));
+    assert_eq!(retry.delay_after(2), Duration::from_millis(300));
+    assert_eq!(retry.delay_after(3), Duration::from_millis(900));
+
+    let constant = RetryPolicy { backoff: 1.0, ..retry };
+    assert_eq!(constant.delay_after(3), Duration::from_millis(100));
+  }
+
+  #[test]
+  fn retried_failures() {
+    let any = RetryPolicy::default();
+    assert!(any.retries(&command_output(Some(1), None)));
+    assert!(any.retries(&command_output(None, None)));
+    assert!(any.retries(&command_output(None, Some(Termination::TimedOut(Duration::from_secs(1))))));
+    assert!(!any.retries(&command_output(None, Some(Termination::Cancelled))));
+
+    let some = RetryPolicy { retry_on: vec![75], ..RetryPolicy::default() };
+    assert!(some.retries(&command_output(Some(75), None)));
+    assert!(!some.retries(&command_output(Some(1), None)));
+    assert!(!some.retries(&command_output(None, None)));
+    assert!(!some.retries(&command_output(None, Some(Termination::TimedOut(Duration::from_secs(1))))));
+  }
+}
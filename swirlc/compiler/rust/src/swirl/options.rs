@@ -0,0 +1,68 @@
+use clap::{builder::RangedU64ValueParser, Args};
+
+use super::MAX_CONNECTIONS;
+
+/// SwirlOptions are the runtime options of a location, parsed from the command line of the generated executable.
+#[derive(Args, Debug, Clone)]
+pub struct SwirlOptions {
+  /// Number of parallel connections used to send a large file, less than the connections of the location (128)
+  #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..MAX_CONNECTIONS as u64))]
+  pub stripes: usize,
+
+  /// Minimum size (in bytes) of a file to be sent over multiple connections
+  #[arg(long, default_value_t = 256 * 1024 * 1024)]
+  pub stripe_threshold: usize,
+
+  /// Do not announce content hashes to skip sending files the destination already stores
+  #[arg(long)]
+  pub no_dedup: bool,
+
+  /// Maximum size (in bytes) of the stdout and stderr logs kept for each step
+  #[arg(long, default_value_t = 64 * 1024 * 1024)]
+  pub step_log_cap: u64,
+
+  /// Timeout (in seconds) of the steps that do not set one
+  #[arg(long)]
+  pub step_timeout: Option<f64>,
+
+  /// Cores available to the steps, detected from the cgroup limits if not set
+  #[arg(long)]
+  pub cores: Option<u32>,
+
+  /// Memory (in MiB) available to the steps, detected from the cgroup limits if not set
+  #[arg(long)]
+  pub memory: Option<u64>,
+
+  /// Place each step in its own cgroup (v2), limited to the cores and memory it declares
+  #[arg(long)]
+  pub cgroups: bool,
+
+  /// Pin each location to a disjoint share of the cores of its node, split among the locations on the node
+  #[arg(long)]
+  pub pin_locations: bool,
+
+  /// Pin each step to the cores it reserves
+  #[arg(long)]
+  pub pin_steps: bool,
+
+  /// Run every step, instead of reusing the cached outputs of a previous run of a step with the same command and inputs.
+  /// The steps without outputs always run
+  #[arg(long)]
+  pub no_cache: bool,
+
+  /// Resume an interrupted run, skipping the steps and receives completed in the journal of the workdir.
+  /// Sends are always replayed, the destinations discard the data they already received.
+  /// Single files interrupted while being sent resume from the bytes received, directories,
+  /// file lists and broadcasts are sent again from the start
+  #[arg(long)]
+  pub resume: bool,
+
+  /// Print the plan of the location (steps, transfers and groups) without running commands or opening connections
+  #[arg(long)]
+  pub dry_run: bool,
+
+  /// Period (in seconds) without progress after which the pending operations are dumped, 10 minutes by default, 0 to disable.
+  /// A running step or a transfer in progress counts as progress, however long it takes
+  #[arg(long, default_value_t = 600.0)]
+  pub stall_timeout: f64,
+}
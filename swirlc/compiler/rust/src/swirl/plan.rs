@@ -0,0 +1,185 @@
+use std::{future::Future, sync::{atomic::Ordering, Arc}};
+
+use tokio::task::JoinSet;
+
+use crate::orchestra::utils::debug_prelude;
+
+use super::{exec::render_argument, PortData, PortID, StepArgument, StepOptions, StepOutput, Swirl};
+
+tokio::task_local! {
+  /// Whether the operations run by the task belong to a skipped group
+  static SKIPPED: bool;
+}
+
+/// Returns true if the current task runs the operations of a skipped group.
+pub(super) fn skipped() -> bool {
+  SKIPPED.try_with(|skipped| *skipped).unwrap_or(false)
+}
+
+/// Returns `future` running with the skipped state of the current task, for a task spawned by it.
+pub(super) fn inherit_skipped<F>(future: F) -> impl Future<Output = F::Output>
+where
+  F: Future,
+{
+  SKIPPED.scope(skipped(), future)
+}
+
+impl Swirl {
+  /**
+   * Runs `group`, the operations of a parenthesized block of the workflow, concurrently
+   * with the other operations of the enclosing block.
+   * In a dry run, the group is walked inline, so that the plan is printed in order.
+   * `NON-BLOCKING`: adds a task to the `JoinSet` and returns the updated `JoinSet`.
+   */
+  pub async fn group<F>(&self, mut join_set: JoinSet<()>, group: F) -> JoinSet<()>
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    if !self.options.dry_run {
+      join_set.spawn(inherit_skipped(group));
+      return join_set;
+    }
+
+    self.plan("group {".to_string());
+    self.plan_depth.fetch_add(1, Ordering::SeqCst);
+
+    group.await;
+
+    self.plan_depth.fetch_sub(1, Ordering::SeqCst);
+    self.plan("}".to_string());
+
+    join_set
+  }
+
+  /**
+   * Runs `group` like `Swirl::group` if the bool port `condition` holds true, otherwise the group is skipped:
+   * its steps are skipped and its sends send skipped data, so that the locations receiving from it do not wait forever.
+   * The receives of a skipped group still run, consuming the data sent to it.
+   * `NON-BLOCKING`: adds a task to the `JoinSet` and returns the updated `JoinSet`.
+   */
+  pub async fn group_when<F>(self: &Arc<Self>, mut join_set: JoinSet<()>, condition: PortID, group: F) -> JoinSet<()>
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    if self.options.dry_run {
+      self.plan(format!("group when {} {{", condition));
+      self.plan_depth.fetch_add(1, Ordering::SeqCst);
+
+      group.await;
+
+      self.plan_depth.fetch_sub(1, Ordering::SeqCst);
+      self.plan("}".to_string());
+      return join_set;
+    }
+
+    let swirl = self.clone();
+    let outer_skipped = skipped();
+
+    join_set.spawn(async move {
+      let port = swirl.ports.get(&condition).expect("port not found");
+
+      // a skipped condition skips the group, as it would skip a step reading it
+      let skipped = outer_skipped || match port.wait_for_data().await {
+        PortData::Bool(value) => !value,
+        PortData::Skip => true,
+        data => panic!("condition {} is not a bool: {:?}", condition, data),
+      };
+
+      if skipped {
+        println!(
+          "{} Skipping group, the condition {} is false",
+          debug_prelude(&swirl.orchestra.self_name(), None),
+          condition,
+        );
+      }
+
+      SKIPPED.scope(skipped, group).await;
+    });
+
+    join_set
+  }
+
+  /// Marks that the next operations follow the previous ones of their block, printed in a dry run.
+  pub fn plan_sequential(&self) {
+    if self.options.dry_run {
+      self.plan("then".to_string());
+    }
+  }
+
+  /// Prints `line` of the plan of a dry run, indented by the depth of the current group.
+  pub(super) fn plan(&self, line: String) {
+    let indent = "  ".repeat(self.plan_depth.load(Ordering::SeqCst));
+
+    println!("[{}] plan: {}{}", self.orchestra.self_name(), indent, line);
+  }
+
+  /// Prints the plan of a step, rendering the arguments whose ports hold data before the run.
+  pub(super) async fn plan_exec(
+    &self,
+    step_name: &String,
+    step_display_name: &String,
+    outputs: &Vec<(PortID, StepOutput)>,
+    cmd: &String,
+    args: &Vec<StepArgument>,
+    step_options: &StepOptions,
+  ) {
+    let mut command_line = vec![cmd.clone()];
+
+    for arg in args {
+      match arg {
+        StepArgument::String(value) => command_line.push(value.clone()),
+        StepArgument::Port(port_id) => command_line.extend(self.plan_values(port_id).await),
+      }
+    }
+
+    let stdin = match &step_options.stdin {
+      Some(StepArgument::String(value)) => format!(" < {:?}", value),
+      Some(StepArgument::Port(port_id)) => format!(" < {}", self.plan_values(port_id).await.join(" ")),
+      None => "".to_string(),
+    };
+
+    let outputs = match outputs.len() {
+      0 => "".to_string(),
+      _ => {
+        let outputs = outputs
+          .iter()
+          .map(|(output_port, output_type)| format!("{} ({:?})", output_port, output_type))
+          .collect::<Vec<_>>();
+
+        format!(" -> {}", outputs.join(", "))
+      }
+    };
+
+    let mode = if step_options.shell { " [shell]" } else { "" };
+
+    let condition = match &step_options.when {
+      Some(condition) => format!(" [when {}]", condition),
+      None => "".to_string(),
+    };
+
+    self.plan(format!(
+      "exec {} ({}): {}{}{}{}{}",
+      step_name,
+      step_display_name,
+      command_line.join(" "),
+      stdin,
+      mode,
+      condition,
+      outputs
+    ));
+  }
+
+  /// Returns the values of the port `port_id` as arguments, `<port>` if it has no data before the run.
+  async fn plan_values(&self, port_id: &PortID) -> Vec<String> {
+    let port = self.ports.get(port_id).expect("port not found");
+
+    match port.get().data {
+      PortData::Empty | PortData::Skip => vec![format!("<{}>", port_id)],
+      data => {
+        let mut values = vec![];
+        render_argument(&data, &mut values);
+        values
+      }
+    }
+  }
+}
@@ -1,14 +1,14 @@
 use std::{path::PathBuf, sync::Arc};
 
-use tokio::task::JoinSet;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, task::JoinSet};
 
 use crate::orchestra::{
   self,
-  utils::{format_bytes, debug_prelude},
+  utils::{extract_archive, format_bytes, debug_prelude},
   LocationID,
 };
 
-use super::{PortData, PortID, Swirl};
+use super::{sequence::message_id, watchdog::Operation, PortData, PortHeader, PortID, Swirl};
 
 impl Swirl {
   pub async fn receive(
@@ -17,6 +17,11 @@ impl Swirl {
     sender: String,
     mut join_set: JoinSet<()>,
   ) -> JoinSet<()> {
+    if self.options.dry_run {
+      self.plan(format!("receive {} from {}", port_id, sender));
+      return join_set;
+    }
+
     println!(
       "{} Receiving data from port {} from {}",
       debug_prelude(&self.orchestra.self_name(), None),
@@ -26,20 +31,58 @@ impl Swirl {
 
     let swirl = self.clone();
     let orchestra = self.orchestra.clone();
-    let sender = orchestra.location_id(&sender);
+
+    // the values of the port are received from the sender in the order they were sent
+    let seq = self.sequences.next_receive(&port_id, &sender);
+    let message = message_id(&port_id, seq);
+
+    // the port is empty until the value arrives, so that the operations following the receive
+    // wait for it instead of reading the previous value of the port
+    self.ports.get(&port_id).expect("port not found").set(PortData::Empty);
+
+    let resumed = match self.options.resume {
+      true => self.resume_receive(&port_id, seq, &sender),
+      false => None,
+    };
+
+    if let Some(data) = resumed {
+      println!(
+        "{} Port {} was received from {} before the interruption, discarding the data sent again",
+        debug_prelude(&self.orchestra.self_name(), None),
+        message,
+        sender
+      );
+
+      join_set.spawn(async move {
+        swirl.complete_receive(&port_id, seq, &sender, data).await;
+
+        let sender = orchestra.location_id(&sender);
+        let received = orchestra.receive_blocking(sender, message).await;
+        swirl.discard(received).await;
+      });
+
+      return join_set;
+    }
+
+    let sender_name = sender;
+    let sender = orchestra.location_id(&sender_name);
     let location = orchestra.location;
     let location = orchestra.location_name(location);
 
-    // clear the existing value
-    self
-      .ports
-      .get(&port_id)
-      .expect("port not found")
-      .set(PortData::Empty)
-      .await;
-
     join_set.spawn(async move {
-      let received = orchestra.receive_blocking(sender, port_id.clone()).await;
+      let receiving = swirl.track(Operation::Receive {
+        origin: sender_name.clone(),
+        port: port_id.clone(),
+      });
+
+      let received = orchestra.receive_blocking(sender, message).await;
+
+      // the data is arriving, the receive is no longer waiting
+      drop(receiving);
+      let _transferring = swirl.track(Operation::Transfer {
+        origin: sender_name.clone(),
+        port: port_id.clone(),
+      });
 
       // println!(
       //   "{} Receiving message from {}",
@@ -47,16 +90,11 @@ impl Swirl {
       //   sender
       // );
 
-      let received_port: PortData = bincode::deserialize(&received.header.header_data)
+      let received_port: PortHeader = bincode::deserialize(&received.header.header_data)
         .expect("failed to deserialize header data");
 
-      let port_data = swirl.ports.get(&port_id).expect("port not found");
-
-      match received_port.clone() {
-        PortData::Empty => {
-          panic!("PortData::Empty should not be received");
-        }
-        PortData::File(file_path) => {
+      match received_port {
+        PortHeader::File(file_path) => {
           let file_name = PathBuf::from(&file_path)
             .file_name()
             .expect("failed to get file name")
@@ -64,12 +102,15 @@ impl Swirl {
 
           let task = swirl.amdahline.begin_task(&location, &format!("receive file {}", file_name));
 
-          let path = swirl.workdir.join(format!(
-            "receive_{}_from_{}",
+          let path = swirl.workdir.join(receive_dir(
             orchestra.location_name(orchestra.location),
-            orchestra.location_name(sender)
+            orchestra.location_name(sender),
+            seq
           ));
-          let size = received.header.size;
+          let size = match &received.header.stripe {
+            Some(stripe) => stripe.total_size,
+            None => received.header.size,
+          };
 
           std::fs::create_dir_all(&path)
             .expect(format!("failed to create directory {:?}", &path).as_str());
@@ -82,11 +123,16 @@ impl Swirl {
             format_bytes(size)
           );
 
-          let file = tokio::fs::File::create(&full_path)
-            .await
-            .expect(format!("failed to create file: {:?}", &full_path).as_str());
-          let writer = tokio::io::BufWriter::new(file);
-          received.collect_blocking_into(writer).await;
+          if received.header.transfer_id.is_some() {
+            // resumable transfer, possibly split into stripes
+            received.collect_blocking_file_transfer(full_path.clone(), swirl.store_dir()).await;
+          } else {
+            let file = tokio::fs::File::create(&full_path)
+              .await
+              .expect(format!("failed to create file: {:?}", &full_path).as_str());
+            let writer = tokio::io::BufWriter::new(file);
+            received.collect_blocking_into(writer).await;
+          }
 
           println!(
             "{} Received file: {:?}, size: {}",
@@ -95,14 +141,96 @@ impl Swirl {
             format_bytes(size)
           );
 
-          port_data.set(PortData::File(full_path.to_str().unwrap().to_string())).await;
-          port_data.port_ready.notify_waiters();
+          swirl.complete_receive(&port_id, seq, &sender_name, PortData::File(full_path.to_str().unwrap().to_string())).await;
 
           swirl.amdahline.end_task(&location, task);
         }
-        _ => {
-          port_data.set(received_port.clone()).await;
-          port_data.port_ready.notify_waiters();
+        header @ (PortHeader::Directory | PortHeader::Files) => {
+          // the body is the manifest followed by the archive, also when the message is relayed to other locations
+          let (writer, mut body) = tokio::io::duplex(64 * 1024);
+
+          let collect = tokio::spawn(async move {
+            let mut writer = received.collect_blocking_into(writer).await;
+            writer.shutdown().await.expect("failed to close message data");
+          });
+
+          let names = read_manifest(&mut body).await;
+
+          let description = match &header {
+            PortHeader::Directory => format!("directory {}", names[0]),
+            _ => format!("{} files", names.len()),
+          };
+
+          let task = swirl.amdahline.begin_task(&location, &format!("receive {}", description));
+
+          let path = swirl.workdir.join(receive_dir(
+            orchestra.location_name(orchestra.location),
+            orchestra.location_name(sender),
+            seq
+          ));
+
+          std::fs::create_dir_all(&path)
+            .expect(format!("failed to create directory {:?}", &path).as_str());
+
+          // previously received entries with the same names are replaced
+          for name in &names {
+            let full_path = path.join(name);
+
+            if full_path.is_dir() {
+              std::fs::remove_dir_all(&full_path)
+                .expect(format!("failed to remove directory {:?}", &full_path).as_str());
+            } else if full_path.exists() {
+              std::fs::remove_file(&full_path)
+                .expect(format!("failed to remove file {:?}", &full_path).as_str());
+            }
+          }
+
+          println!(
+            "{} Receiving {} into: {:?}",
+            debug_prelude(&orchestra.self_name(), None),
+            description,
+            path
+          );
+
+          let mut tar = extract_archive(&path);
+          let mut writer = tar.stdin.take().expect("failed to get archive input");
+
+          tokio::io::copy(&mut body, &mut writer).await.expect("failed to extract archive");
+          writer.shutdown().await.expect("failed to close archive input");
+          drop(writer);
+
+          collect.await.expect("failed to receive archive");
+
+          let status = tar.wait().await.expect("failed to wait for archive");
+          assert!(status.success(), "failed to extract {} into {:?}: {}", description, path, status);
+
+          println!(
+            "{} Received {} into: {:?}",
+            debug_prelude(&orchestra.self_name(), None),
+            description,
+            path
+          );
+
+          let full_path = |name: &String| path.join(name).to_str().unwrap().to_string();
+
+          let data = match header {
+            PortHeader::Directory => PortData::Directory(full_path(&names[0])),
+            _ => PortData::Files(names.iter().map(full_path).collect()),
+          };
+
+          swirl.complete_receive(&port_id, seq, &sender_name, data).await;
+
+          swirl.amdahline.end_task(&location, task);
+        }
+        PortHeader::Value => {
+          let data: PortData = bincode::deserialize(&received.collect_blocking_vecu8().await)
+            .expect("failed to deserialize port data");
+
+          if data.is_empty() {
+            panic!("PortData::Empty should not be received");
+          }
+
+          swirl.complete_receive(&port_id, seq, &sender_name, data).await;
         }
       }
 
@@ -111,3 +239,27 @@ impl Swirl {
     join_set
   }
 }
+
+/// Reads the manifest of an archive (see `encode_manifest`), the names of its entries.
+async fn read_manifest<R>(reader: &mut R) -> Vec<String>
+where
+  R: AsyncRead + Unpin,
+{
+  let length = reader.read_u64().await.expect("failed to read manifest");
+
+  let mut names = vec![0; length as usize];
+  reader.read_exact(&mut names).await.expect("failed to read manifest");
+
+  bincode::deserialize(&names).expect("failed to deserialize manifest")
+}
+
+/**
+ * Returns the name of the directory the data received from `sender` is stored into.
+ * The values after the first get their own directory, so that they do not replace the previous ones.
+ */
+fn receive_dir(location: String, sender: String, seq: u64) -> String {
+  match seq {
+    0 => format!("receive_{}_from_{}", location, sender),
+    seq => format!("receive_{}_from_{}_{}", location, sender, seq),
+  }
+}
@@ -0,0 +1,239 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::orchestra::{affinity::current_cores, cgroup};
+
+/// ResourcePool holds the cores and the memory (in MiB) available to the steps of a location.
+pub struct ResourcePool {
+  pub cores: u32,
+  pub memory: u64,
+  core_slots: Semaphore,
+  memory_slots: Semaphore,
+  /// ids of the free cores, a core id is repeated if the pool has more cores than the process can use
+  free_core_ids: Mutex<Vec<usize>>,
+}
+
+/// Resources is a reservation of a ResourcePool, released when dropped.
+pub struct Resources<'a> {
+  pub cores: u32,
+  pub memory: u64,
+  /// ids of the reserved cores, the step can be pinned to them
+  pub core_ids: Vec<usize>,
+  pool: &'a ResourcePool,
+  _core_permit: SemaphorePermit<'a>,
+  _memory_permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for Resources<'_> {
+  fn drop(&mut self) {
+    self
+      .pool
+      .free_core_ids
+      .lock()
+      .expect("failed to lock core ids")
+      .extend(self.core_ids.drain(..));
+  }
+}
+
+impl ResourcePool {
+  /// Creates a pool of `cores` and `memory` (in MiB), detecting the ones that are not set
+  /// from the cgroup limits of the process, or else from the whole machine.
+  pub fn new(cores: Option<u32>, memory: Option<u64>) -> Self {
+    let cores = cores.unwrap_or_else(detect_cores).max(1);
+    let memory = memory.unwrap_or_else(detect_memory);
+
+    Self::with_core_ids(cores, memory, current_cores())
+  }
+
+  /// Creates a pool of `cores` and `memory` (in MiB), whose cores are pinned to the `core_ids` the process can use.
+  fn with_core_ids(cores: u32, memory: u64, core_ids: Vec<usize>) -> Self {
+    let free_core_ids = match core_ids.len() {
+      0 => vec![],
+      count => (0..cores as usize).rev().map(|core| core_ids[core % count]).collect(),
+    };
+
+    ResourcePool {
+      cores,
+      memory,
+      core_slots: Semaphore::new(cores as usize),
+      memory_slots: Semaphore::new(memory as usize),
+      free_core_ids: Mutex::new(free_core_ids),
+    }
+  }
+
+  /**
+   * Reserves `cores` and `memory` (in MiB), requests over the size of the pool are reduced to it.
+   * BLOCKING: waits for the resources to be released by the running steps.
+   */
+  pub async fn acquire(&self, cores: u32, memory: u64) -> Resources<'_> {
+    let cores = cores.clamp(1, self.cores);
+    let memory = memory.min(self.memory);
+
+    // cores are always reserved first, so that two steps never wait for each other
+    let core_permit = self.core_slots.acquire_many(cores).await.expect("failed to acquire cores");
+
+    let memory_permit = match memory {
+      0 => None,
+      memory => Some(
+        self
+          .memory_slots
+          .acquire_many(memory as u32)
+          .await
+          .expect("failed to acquire memory"),
+      ),
+    };
+
+    let core_ids = {
+      let mut free_core_ids = self.free_core_ids.lock().expect("failed to lock core ids");
+      let count = std::cmp::min(cores as usize, free_core_ids.len());
+      let start = free_core_ids.len() - count;
+      free_core_ids.split_off(start)
+    };
+
+    Resources {
+      cores,
+      memory,
+      core_ids,
+      pool: self,
+      _core_permit: core_permit,
+      _memory_permit: memory_permit,
+    }
+  }
+}
+
+/**
+ * Returns the cores of the cgroup quota (v2 or v1) of the process, or else the available ones.
+ * The cgroup is the one of the process, and its quota is the smallest of it and its ancestors.
+ */
+fn detect_cores() -> u32 {
+  let available = std::thread::available_parallelism()
+    .map(|cores| cores.get() as u32)
+    .unwrap_or(1);
+
+  // cgroup v2: "<quota> <period>" or "max <period>"
+  let quota = smallest(cgroup::own_cgroups(None), |cgroup| {
+    let cpu_max = std::fs::read_to_string(cgroup.join("cpu.max")).ok()?;
+    let mut fields = cpu_max.split_whitespace();
+    let quota = fields.next()?.parse::<f64>().ok()?;
+    let period = fields.next()?.parse::<f64>().ok()?;
+    Some(quota / period)
+  })
+  // cgroup v1: quota is -1 if unlimited
+  .or_else(|| {
+    smallest(cgroup::own_cgroups(Some("cpu")), |cgroup| {
+      let quota = read_number(&cgroup.join("cpu.cfs_quota_us"))? as f64;
+      let period = read_number(&cgroup.join("cpu.cfs_period_us"))? as f64;
+      (quota > 0.0).then(|| quota / period)
+    })
+  });
+
+  match quota {
+    Some(quota) => (quota.ceil() as u32).clamp(1, available),
+    None => available,
+  }
+}
+
+/**
+ * Returns the memory (in MiB) of the cgroup limit (v2 or v1) of the process, or else the total one.
+ * The cgroup is the one of the process, and its limit is the smallest of it and its ancestors.
+ */
+fn detect_memory() -> u64 {
+  let total = std::fs::read_to_string("/proc/meminfo")
+    .ok()
+    .and_then(|meminfo| {
+      let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+      line.split_whitespace().nth(1)?.parse::<u64>().ok()
+    })
+    .map(|kb| kb / 1024)
+    .unwrap_or(0);
+
+  // cgroup v2 is "max" if unlimited, v1 is a huge number if unlimited
+  let limit = smallest(cgroup::own_cgroups(None), |cgroup| read_number(&cgroup.join("memory.max")))
+    .or_else(|| {
+      smallest(cgroup::own_cgroups(Some("memory")), |cgroup| {
+        read_number(&cgroup.join("memory.limit_in_bytes"))
+      })
+    })
+    .map(|bytes| bytes / (1024 * 1024));
+
+  match limit {
+    Some(limit) if total == 0 || limit < total => limit,
+    _ => total,
+  }
+}
+
+/// Returns the smallest of the limits read from `cgroups`, None if none of them is limited.
+fn smallest<T, F>(cgroups: Vec<PathBuf>, limit: F) -> Option<T>
+where
+  T: PartialOrd,
+  F: Fn(&PathBuf) -> Option<T>,
+{
+  cgroups
+    .iter()
+    .filter_map(limit)
+    .reduce(|smallest, limit| if limit < smallest { limit } else { smallest })
+}
+
+fn read_number(path: &PathBuf) -> Option<u64> {
+  std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn requests_are_clamped_to_the_pool() {
+    let pool = ResourcePool::with_core_ids(4, 1024, vec![0, 1, 2, 3]);
+
+    let resources = pool.acquire(16, 4096).await;
+    assert_eq!((resources.cores, resources.memory), (4, 1024));
+    assert_eq!(resources.core_ids.len(), 4);
+    drop(resources);
+
+    // a step reserves at least a core, and no memory unless it declares some
+    let resources = pool.acquire(0, 0).await;
+    assert_eq!((resources.cores, resources.memory), (1, 0));
+    assert_eq!(pool.memory_slots.available_permits(), 1024);
+  }
+
+  #[tokio::test]
+  async fn released_resources_are_reused() {
+    let pool = ResourcePool::with_core_ids(2, 100, vec![5, 7]);
+
+    let first = pool.acquire(1, 60).await;
+    let second = pool.acquire(1, 40).await;
+    assert_ne!(first.core_ids, second.core_ids);
+    assert_eq!(pool.core_slots.available_permits(), 0);
+
+    let waiting = pool.acquire(1, 50);
+    tokio::pin!(waiting);
+    assert!(ready_soon(&mut waiting).await.is_none());
+
+    let first_core_ids = first.core_ids.clone();
+    drop(first);
+
+    let third = waiting.await;
+    assert_eq!(third.core_ids, first_core_ids);
+  }
+
+  #[tokio::test]
+  async fn core_ids_are_repeated_when_the_pool_is_larger() {
+    let pool = ResourcePool::with_core_ids(4, 0, vec![0, 1]);
+
+    let resources = pool.acquire(4, 0).await;
+    let mut core_ids = resources.core_ids.clone();
+    core_ids.sort();
+    assert_eq!(core_ids, vec![0, 0, 1, 1]);
+
+    // a pool without usable core ids reserves cores without pinning them
+    let pool = ResourcePool::with_core_ids(2, 0, vec![]);
+    assert!(pool.acquire(2, 0).await.core_ids.is_empty());
+  }
+
+  /// Returns the output of `future` if it is ready within a short time.
+  async fn ready_soon<F: std::future::Future + Unpin>(future: &mut F) -> Option<F::Output> {
+    tokio::time::timeout(std::time::Duration::from_millis(50), future).await.ok()
+  }
+}
@@ -1,11 +1,11 @@
 use std::{path::PathBuf, sync::Arc};
 
 use bytes::Bytes;
-use tokio::task::JoinSet;
+use tokio::{io::AsyncReadExt, task::JoinSet};
 
-use crate::orchestra::{self, utils::{debug_prelude, format_bytes}, LocationID};
+use crate::orchestra::{self, utils::{archive, debug_prelude, format_bytes, paths_size}, LocationID};
 
-use super::{PortData, PortID, Swirl};
+use super::{encode_manifest, plan::skipped, sequence::message_id, watchdog::Operation, PortData, PortHeader, PortID, Swirl};
 
 impl Swirl {
   pub async fn send(
@@ -14,6 +14,16 @@ impl Swirl {
     destination: String,
     mut join_set: JoinSet<()>,
   ) -> JoinSet<()> {
+    if self.options.dry_run {
+      self.plan(format!("send {} to {}", port_id, destination));
+      return join_set;
+    }
+
+    // the values of the port sent to the destination are numbered, and received in order
+    let seq = self.sequences.next_send(&port_id, &destination);
+    let message = message_id(&port_id, seq);
+
+    let destinations = vec![destination.clone()];
     let destination = self.orchestra.location_id(&destination);
 
     //============================ Copy Data ============================
@@ -22,22 +32,38 @@ impl Swirl {
     //===================================================================
     let port = self.ports.get(&port_id).expect("port not found");
 
-    port.wait_for_data().await;
-
-    let data_read = port.value.read().await;
-    let data = data_read.clone();
+    // a skipped group sends skipped data, whatever the port holds
+    let data = match skipped() {
+      true => PortData::Skip,
+      false => port.wait_for_data().await,
+    };
 
     let location = self.orchestra.location;
     let location = self.orchestra.location_name(location);
 
-    drop(data_read);
-
     let handle = match data {
       PortData::File(path) => {
         let swirl = self.clone();
 
         join_set.spawn(async move {
-          let permit = swirl.connection_limit.acquire_many(2).await.unwrap();
+          let _sending = swirl.track(Operation::Send {
+            port: port_id.clone(),
+            destinations: destinations.clone(),
+          });
+
+          let file_size = tokio::fs::metadata(&path)
+            .await
+            .expect(format!("failed to read metadata of file: {:?}", path).as_str())
+            .len() as usize;
+
+          // large files are split into stripes, each sent over its own connection
+          let stripes = if file_size >= swirl.options.stripe_threshold {
+            swirl.options.stripes.max(1)
+          } else {
+            1
+          };
+
+          let permit = swirl.connection_limit.acquire_many(1 + stripes as u32).await.unwrap();
 
           let file_name = PathBuf::from(&path)
             .file_name()
@@ -46,29 +72,84 @@ impl Swirl {
 
           let task = swirl.amdahline.begin_task(&location, &format!("send file {}", file_name));
 
-          let file = tokio::fs::File::options()
-            .read(true)
-            .open(&path)
-            .await
-            .expect(format!("failed to open file: {:?}", path).as_str());
+          let content_hash = if swirl.options.no_dedup {
+            None
+          } else {
+            Some(swirl.content_hash(&path).await)
+          };
+
+          let header_data = PortHeader::File(file_name);
+          let header_data = bincode::serialize(&header_data).unwrap();
+          let header_data = Bytes::from(header_data);
+
+          println!("{} Sending file data to {} in {} stripe(s), size: {}", debug_prelude(&swirl.orchestra.self_name(), None), destination, stripes, format_bytes(file_size));
 
-          let file_size = file.metadata().await.unwrap().len() as usize;
+          swirl.orchestra.blocking_send_striped(
+            destination,
+            message.clone(),
+            PathBuf::from(&path),
+            header_data,
+            file_size,
+            stripes,
+            content_hash,
+            swirl.orchestra.location
+          ).await;
+
+          swirl.amdahline.end_task(&location, task);
+
+          drop(permit);
+        });
+
+        return join_set;
+      }
+      data @ (PortData::Directory(_) | PortData::Files(_)) => {
+        let swirl = self.clone();
+
+        let (paths, names) = data.archive_entries().unwrap();
+
+        let header_data = match data {
+          PortData::Directory(_) => PortHeader::Directory,
+          _ => PortHeader::Files,
+        };
+
+        join_set.spawn(async move {
+          let _sending = swirl.track(Operation::Send {
+            port: port_id.clone(),
+            destinations: destinations.clone(),
+          });
+
+          let permit = swirl.connection_limit.acquire_many(2).await.unwrap();
+
+          let description = match &header_data {
+            PortHeader::Directory => format!("directory {}", names[0]),
+            _ => format!("{} files", names.len()),
+          };
+
+          let task = swirl.amdahline.begin_task(&location, &format!("send {}", description));
+
+          // the data is streamed as a tar archive after its manifest, the size is only indicative
+          let manifest = encode_manifest(&names);
+          let size = manifest.len() + paths_size(&paths);
+          let mut tar = archive(&paths);
+          let reader = std::io::Cursor::new(manifest).chain(tar.stdout.take().expect("failed to get archive output"));
 
-          let header_data = PortData::File(file_name);
           let header_data = bincode::serialize(&header_data).unwrap();
           let header_data = Bytes::from(header_data);
 
-          println!("{} Sending file data to {}, size: {}", debug_prelude(&swirl.orchestra.self_name(), None), destination, format_bytes(file_size));
+          println!("{} Sending {} to {}, size: {}", debug_prelude(&swirl.orchestra.self_name(), None), description, destination, format_bytes(size));
 
           swirl.orchestra.blocking_send(
             destination,
-            port_id,
-            file,
+            message.clone(),
+            reader,
             header_data,
-            file_size,
+            size,
             swirl.orchestra.location
           ).await;
 
+          let status = tar.wait().await.expect("failed to wait for archive");
+          assert!(status.success(), "failed to archive {:?}: {}", paths, status);
+
           swirl.amdahline.end_task(&location, task);
 
           drop(permit);
@@ -81,19 +162,32 @@ impl Swirl {
         panic!("empty data");
       }
       data => {
+        // the value is the message body, the header only tells its kind
         let data = bincode::serialize(&data).unwrap();
         let size = data.len();
+        let header_data = Bytes::from(bincode::serialize(&PortHeader::Value).unwrap());
 
         println!("{} Sending data to {}, size: {}", debug_prelude(&self.orchestra.self_name(), None), destination, format_bytes(size));
 
-        self.orchestra.send_joinset(
-          destination,
-          port_id,
-          tokio::io::empty(),
-          Bytes::from(data),
-          size,
-          join_set
-        )
+        let swirl = self.clone();
+
+        join_set.spawn(async move {
+          let _sending = swirl.track(Operation::Send {
+            port: port_id.clone(),
+            destinations: destinations.clone(),
+          });
+
+          swirl.orchestra.blocking_send(
+            destination,
+            message.clone(),
+            std::io::Cursor::new(data),
+            header_data,
+            size,
+            swirl.orchestra.location
+          ).await;
+        });
+
+        join_set
       }
     };
 
@@ -0,0 +1,175 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use tokio::sync::watch;
+
+use super::PortID;
+
+/**
+ * Returns the id of the message carrying the `seq`-th value of the port `port_id` sent to a peer.
+ * The first value keeps the port id, so that a port sent once is sent as before.
+ */
+pub fn message_id(port_id: &PortID, seq: u64) -> String {
+  match seq {
+    0 => port_id.clone(),
+    seq => format!("{}#{}", port_id, seq),
+  }
+}
+
+/**
+ * Sequences numbers the values of each port sent to (or received from) each peer, in program order,
+ * so that a port can carry many values between two locations: the receiver takes the messages
+ * in the order they were sent, and sets the port in that order.
+ */
+#[derive(Default)]
+pub struct Sequences {
+  /// next value of each (port, destination)
+  sent: Mutex<HashMap<(PortID, String), u64>>,
+  /// next value of each (port, sender)
+  received: Mutex<HashMap<(PortID, String), u64>>,
+  /// values of each (port, sender) already set on the port
+  delivered: Mutex<HashMap<(PortID, String), Arc<watch::Sender<u64>>>>,
+}
+
+impl Sequences {
+  /// Returns the number of the next value of the port `port_id` sent to `destination`.
+  pub fn next_send(&self, port_id: &PortID, destination: &String) -> u64 {
+    let mut sent = self.sent.lock().expect("failed to lock sequences");
+    let seq = sent.entry((port_id.clone(), destination.clone())).or_insert(0);
+
+    *seq += 1;
+    *seq - 1
+  }
+
+  /**
+   * Returns the number of the next value of the port `port_id` sent to all the `destinations`,
+   * None (numbering nothing) if the destinations received a different number of values of the port.
+   */
+  pub fn next_broadcast(&self, port_id: &PortID, destinations: &Vec<String>) -> Option<u64> {
+    let mut sent = self.sent.lock().expect("failed to lock sequences");
+
+    let seqs = destinations
+      .iter()
+      .map(|destination| *sent.get(&(port_id.clone(), destination.clone())).unwrap_or(&0))
+      .collect::<Vec<_>>();
+
+    if seqs.iter().any(|seq| *seq != seqs[0]) {
+      return None;
+    }
+
+    for destination in destinations {
+      sent.insert((port_id.clone(), destination.clone()), seqs[0] + 1);
+    }
+
+    Some(seqs[0])
+  }
+
+  /// Returns the number of the next value of the port `port_id` received from `sender`.
+  pub fn next_receive(&self, port_id: &PortID, sender: &String) -> u64 {
+    let mut received = self.received.lock().expect("failed to lock sequences");
+    let seq = received.entry((port_id.clone(), sender.clone())).or_insert(0);
+
+    *seq += 1;
+    *seq - 1
+  }
+
+  /// Returns the number of the next value of the port `port_id` received from `sender`, without numbering it.
+  pub fn peek_receive(&self, port_id: &PortID, sender: &String) -> u64 {
+    let received = self.received.lock().expect("failed to lock sequences");
+
+    *received.get(&(port_id.clone(), sender.clone())).unwrap_or(&0)
+  }
+
+  /**
+   * Sets the `seq`-th value of the port `port_id` received from `sender` with `set`,
+   * after the previous values received from `sender`.
+   * BLOCKING: waits for the previous values to be set.
+   */
+  pub async fn deliver<F>(&self, port_id: &PortID, sender: &String, seq: u64, set: F)
+  where
+    F: FnOnce(),
+  {
+    let delivered = self
+      .delivered
+      .lock()
+      .expect("failed to lock sequences")
+      .entry((port_id.clone(), sender.clone()))
+      .or_insert_with(|| Arc::new(watch::channel(0).0))
+      .clone();
+
+    delivered
+      .subscribe()
+      .wait_for(|delivered| *delivered == seq)
+      .await
+      .expect("failed to wait for the previous values");
+
+    set();
+
+    delivered.send_modify(|delivered| *delivered += 1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn message_ids_number_the_values() {
+    assert_eq!(message_id(&"p1".into(), 0), "p1");
+    assert_eq!(message_id(&"p1".into(), 2), "p1#2");
+  }
+
+  #[test]
+  fn values_are_numbered_per_port_and_peer() {
+    let sequences = Sequences::default();
+    let (p1, p2): (PortID, PortID) = ("p1".into(), "p2".into());
+    let (l1, l2) = ("l1".to_string(), "l2".to_string());
+
+    assert_eq!(sequences.next_send(&p1, &l1), 0);
+    assert_eq!(sequences.next_send(&p1, &l1), 1);
+    assert_eq!(sequences.next_send(&p1, &l2), 0);
+    assert_eq!(sequences.next_send(&p2, &l1), 0);
+
+    assert_eq!(sequences.peek_receive(&p1, &l1), 0);
+    assert_eq!(sequences.next_receive(&p1, &l1), 0);
+    assert_eq!(sequences.peek_receive(&p1, &l1), 1);
+    assert_eq!(sequences.next_receive(&p1, &l1), 1);
+    assert_eq!(sequences.next_receive(&p1, &l2), 0);
+  }
+
+  #[test]
+  fn broadcast_is_numbered_only_if_the_destinations_agree() {
+    let sequences = Sequences::default();
+    let p1: PortID = "p1".into();
+    let destinations = vec!["l1".to_string(), "l2".to_string()];
+
+    assert_eq!(sequences.next_broadcast(&p1, &destinations), Some(0));
+    assert_eq!(sequences.next_broadcast(&p1, &destinations), Some(1));
+
+    sequences.next_send(&p1, &destinations[0]);
+    assert_eq!(sequences.next_broadcast(&p1, &destinations), None);
+    assert_eq!(sequences.next_send(&p1, &destinations[1]), 2);
+  }
+
+  #[tokio::test]
+  async fn values_are_delivered_in_order() {
+    let sequences = Arc::new(Sequences::default());
+    let delivered = Arc::new(Mutex::new(vec![]));
+    let (p1, l1): (PortID, String) = ("p1".into(), "l1".to_string());
+
+    // the second value arrives first, it is set after the first one
+    let second = tokio::spawn({
+      let (sequences, delivered, p1, l1) = (sequences.clone(), delivered.clone(), p1.clone(), l1.clone());
+      async move {
+        sequences.deliver(&p1, &l1, 1, || delivered.lock().unwrap().push(1)).await;
+      }
+    });
+
+    tokio::task::yield_now().await;
+    assert!(delivered.lock().unwrap().is_empty());
+
+    sequences.deliver(&p1, &l1, 0, || delivered.lock().unwrap().push(0)).await;
+    second.await.unwrap();
+
+    assert_eq!(*delivered.lock().unwrap(), vec![0, 1]);
+  }
+}
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{amdahline::Amdahline, orchestra::utils::debug_prelude};
+
+use super::{Port, PortID, Swirl};
+
+#[derive(Debug, Clone)]
+/// Operation is a pending operation of a location, reported when the location stalls.
+pub enum Operation {
+  /// Waiting for the data of the port from the origin
+  Receive { origin: String, port: PortID },
+  /// Receiving the data of the port from the origin
+  Transfer { origin: String, port: PortID },
+  /// Sending the data of the port to the destinations
+  Send { port: PortID, destinations: Vec<String> },
+  /// Running the command of the step
+  Step { name: String },
+  /// Waiting for the guard of a branch of a choice to be ready
+  Choice { guards: Vec<String> },
+}
+
+impl std::fmt::Display for Operation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Operation::Receive { origin, port } => write!(f, "receive of port {} from {}", port, origin),
+      Operation::Transfer { origin, port } => write!(f, "transfer of port {} from {}", port, origin),
+      Operation::Send { port, destinations } => write!(f, "send of port {} to {}", port, destinations.join(", ")),
+      Operation::Step { name } => write!(f, "step {}", name),
+      Operation::Choice { guards } => write!(f, "choice between {}", guards.join(", ")),
+    }
+  }
+}
+
+impl Operation {
+  /// Returns true if the operation is working rather than waiting: a running command or a transfer is progress, however long it takes.
+  fn active(&self) -> bool {
+    matches!(self, Operation::Step { .. } | Operation::Send { .. } | Operation::Transfer { .. })
+  }
+}
+
+/// Activity holds the pending operations of a location and the time it last made progress.
+pub struct Activity {
+  last_progress: Instant,
+  next_id: u64,
+  pending: HashMap<u64, (Operation, Instant)>,
+}
+
+impl Activity {
+  pub fn new() -> Self {
+    Activity {
+      last_progress: Instant::now(),
+      next_id: 0,
+      pending: HashMap::new(),
+    }
+  }
+}
+
+/// Tracked is a pending operation, completed (which is progress) when dropped.
+pub struct Tracked {
+  activity: Arc<Mutex<Activity>>,
+  id: u64,
+}
+
+impl Drop for Tracked {
+  fn drop(&mut self) {
+    let mut activity = self.activity.lock().expect("failed to lock activity");
+
+    activity.pending.remove(&self.id);
+    activity.last_progress = Instant::now();
+  }
+}
+
+impl Swirl {
+  /// Tracks `operation` as pending until the returned value is dropped.
+  pub(super) fn track(&self, operation: Operation) -> Tracked {
+    let mut activity = self.activity.lock().expect("failed to lock activity");
+
+    let id = activity.next_id;
+    activity.next_id += 1;
+    activity.pending.insert(id, (operation, Instant::now()));
+
+    Tracked {
+      activity: self.activity.clone(),
+      id,
+    }
+  }
+
+  /// Records that the location made progress, e.g. the data of a port was set.
+  pub(super) fn progress(&self) {
+    self.activity.lock().expect("failed to lock activity").last_progress = Instant::now();
+  }
+}
+
+/**
+ * Spawns the watchdog of the location, which dumps its pending operations and the ports
+ * waited for while still empty, every `period` without progress.
+ * The location is never stalled while a step runs or data is transferred.
+ */
+pub fn spawn_watchdog(
+  location: String,
+  period: Duration,
+  ports: Arc<HashMap<PortID, Port>>,
+  activity: Arc<Mutex<Activity>>,
+  amdahline: Arc<Amdahline>,
+) {
+  tokio::spawn(async move {
+    let mut last_report: Option<Instant> = None;
+
+    loop {
+      tokio::time::sleep(period.min(Duration::from_secs(1))).await;
+
+      let (last_progress, mut pending) = {
+        let mut activity = activity.lock().expect("failed to lock activity");
+
+        if activity.pending.values().any(|(operation, _)| operation.active()) {
+          activity.last_progress = Instant::now();
+        }
+
+        let pending = activity.pending.values().cloned().collect::<Vec<_>>();
+
+        (activity.last_progress, pending)
+      };
+
+      // a stall is reported again every period it lasts
+      let stalled_since = match last_report {
+        Some(last_report) => last_report.max(last_progress),
+        None => last_progress,
+      };
+
+      if stalled_since.elapsed() < period {
+        continue;
+      }
+
+      let mut waited_ports = vec![];
+      for (port_id, port) in ports.iter() {
+        let waiters = port.waiters();
+
+        if waiters > 0 && port.get().data.is_empty() {
+          waited_ports.push((port_id.clone(), waiters));
+        }
+      }
+      waited_ports.sort();
+
+      // the longest pending operations first
+      pending.sort_by_key(|(_, since)| *since);
+
+      let mut report = vec![format!(
+        "No progress for {:.1?}: {} pending operations, {} ports waited for",
+        last_progress.elapsed(),
+        pending.len(),
+        waited_ports.len()
+      )];
+
+      for (port_id, waiters) in waited_ports {
+        report.push(format!("port {} is empty, waited for by {} operations", port_id, waiters));
+      }
+      for (operation, since) in pending {
+        report.push(format!("{} pending for {:.1?}", operation, since.elapsed()));
+      }
+
+      for line in &report {
+        eprintln!("{} {}", debug_prelude(&location, None), line);
+        amdahline.annotate_executor(&location, "stall", line);
+      }
+      amdahline.flush();
+
+      last_report = Some(Instant::now());
+    }
+  });
+}
@@ -1,9 +1,12 @@
 from __future__ import annotations
 
 from collections import defaultdict
+import io
+import json
+import math
 import os
 import sys
-from typing import MutableMapping, MutableSequence, TextIO
+from typing import Any, MutableMapping, MutableSequence, TextIO
 import shutil
 from ruamel.yaml import YAML
 
@@ -13,13 +16,121 @@ from swirlc.compiler.rust.run_script import build_run_script
 from swirlc.compiler.rust.location_main import start_location_file, close_location_file
 from swirlc.compiler.rust.rust_lib import build_locations_module, build_main_file, build_rust_lib
 from swirlc.core.compiler import BaseCompiler
-from swirlc.core.entity import Location, Step, Port, Workflow, DistributedWorkflow, Data
+from swirlc.core.entity import Location, Step, Port, Processor, Workflow, DistributedWorkflow, Data
 from swirlc.version import VERSION
 
 # "release" | "debug" | "none"
 BUILD_MODE = "none"
 ENABLE_BROADCAST = True
 
+
+def rust_string(value: Any) -> str:
+    """Returns `value` as a Rust string literal."""
+    escaped = str(value).replace("\\", "\\\\").replace('"', '\\"').replace("\n", "\\n")
+    return f'"{escaped}"'
+
+
+def port_data_literal(data_type: str, value: Any) -> str:
+    """Returns the Rust `PortData` expression holding `value` as data of type `data_type`."""
+    if data_type == "file":
+        return f"PortData::File({rust_string(value)}.to_string())"
+    elif data_type == "directory":
+        return f"PortData::Directory({rust_string(value)}.to_string())"
+    elif data_type == "string":
+        return f"PortData::String({rust_string(value)}.to_string())"
+    elif data_type == "int":
+        return f"PortData::Int({int(value)})"
+    elif data_type == "int64":
+        return f"PortData::Int64({int(value)})"
+    elif data_type == "float":
+        value = float(value)
+        if math.isnan(value):
+            return "PortData::Float(f64::NAN)"
+        elif math.isinf(value):
+            return f"PortData::Float({'' if value > 0 else '-'}f64::INFINITY)"
+        return f"PortData::Float({value!r})"
+    elif data_type == "bool":
+        value = value if isinstance(value, bool) else str(value).lower() == "true"
+        return f"PortData::Bool({str(value).lower()})"
+    elif data_type == "list":
+        values = json.loads(value) if isinstance(value, str) else value
+        elements = ", ".join([port_data_literal(infer_data_type(v), v) for v in values])
+        return f"PortData::List(vec![{elements}])"
+    elif data_type == "json":
+        document = json.loads(value) if isinstance(value, str) else value
+        return f"PortData::Json({rust_string(json.dumps(document))}.to_string())"
+    else:
+        raise ValueError(f"Unsupported data type: {data_type}")
+
+
+def infer_data_type(value: Any) -> str:
+    """Returns the data type of a list element, based on its YAML type."""
+    if isinstance(value, bool):
+        return "bool"
+    elif isinstance(value, int):
+        return "int" if -(2**31) <= value < 2**31 else "int64"
+    elif isinstance(value, float):
+        return "float"
+    elif isinstance(value, list):
+        return "list"
+    elif isinstance(value, dict):
+        return "json"
+    else:
+        return "string"
+
+
+STDOUT_OUTPUT_TYPES = {
+    "string": "OutputType::String",
+    "int": "OutputType::Int",
+    "int64": "OutputType::Int64",
+    "float": "OutputType::Float",
+    "bool": "OutputType::Bool",
+    "json": "OutputType::Json",
+}
+
+
+def step_output_literal(step_name: str, processor: Processor) -> str:
+    """Returns the Rust `StepOutput` expression describing how the output of a step is collected."""
+    if processor.stdout:
+        if processor.type not in STDOUT_OUTPUT_TYPES:
+            raise ValueError(
+                f"Step {step_name} cannot read data of type {processor.type} from the standard output"
+            )
+        return f"StepOutput::Stdout({STDOUT_OUTPUT_TYPES[processor.type]})"
+    return f"StepOutput::File({rust_string(processor.glob)}.to_string())"
+
+
+def resource_limits_literal(limits: MutableMapping[str, int]) -> str:
+    """Returns the Rust `ResourceLimits` expression of the `limits` section of a step."""
+    fields = []
+    if "addressSpace" in limits:
+        fields.append(f"address_space: Some({int(limits['addressSpace'])} * 1024 * 1024)")
+    if "cpuTime" in limits:
+        fields.append(f"cpu_time: Some({int(limits['cpuTime'])})")
+    if "openFiles" in limits:
+        fields.append(f"open_files: Some({int(limits['openFiles'])})")
+    return f"ResourceLimits {{ {', '.join(fields)}, ..Default::default() }}"
+
+
+FAILURE_POLICIES = {
+    "abort": "FailurePolicy::Abort",
+    "skip": "FailurePolicy::Skip",
+    "continue": "FailurePolicy::Continue",
+}
+
+
+def retry_policy_literal(retry: MutableMapping[str, Any]) -> str:
+    """Returns the Rust `RetryPolicy` expression of the `retry` section of a step."""
+    fields = [f"max_attempts: {int(retry['maxAttempts'])}"]
+    if "delay" in retry:
+        fields.append(f"delay: std::time::Duration::from_secs_f64({float(retry['delay'])!r})")
+    if "backoff" in retry:
+        fields.append(f"backoff: {float(retry['backoff'])!r}")
+    if "retryOn" in retry:
+        fields.append(f"retry_on: vec![{', '.join(str(int(code)) for code in retry['retryOn'])}]")
+    return f"RetryPolicy {{ {', '.join(fields)}, ..Default::default() }}"
+
+
 class ThreadStack:
     def __init__(self) -> None:
         self.stack: MutableSequence[int] = []
@@ -43,6 +154,28 @@ class ThreadStack:
     def len(self) -> int:
         return len(self.stack)
 
+class Choice:
+    def __init__(self, program: TextIO) -> None:
+        # the choice is written to the program once all its branches are compiled
+        self.program = program
+        self.branches: MutableSequence[tuple[str, str]] = []
+        # the guard of the current branch, its first receive if it starts with one
+        self.guard = "Guard::Ready"
+        self.started = False
+        # whether the choice starts a branch of the enclosing choice
+        self.starts_branch = False
+        # the choice ending the current branch and the length of the branch when it ended,
+        # the branches of a choice that is a whole branch are added to this one, e.g. in `a + b + c`
+        self.last: tuple[Choice, int] | None = None
+
+class Group:
+    def __init__(self, program: TextIO) -> None:
+        # the group is written to the program once its condition is known
+        self.program = program
+        self.started = False
+        # the bool port the whole group runs on, from the `when` of the step starting it
+        self.condition: str | None = None
+
 class RustTarget(BaseCompiler):
     def __init__(self, output_dir: str, env: str) -> None:
         super().__init__()
@@ -57,6 +190,10 @@ class RustTarget(BaseCompiler):
 
         self.broadcast_stack: dict[str, list[str]] = defaultdict(list)
         self.thread_stack: ThreadStack = ThreadStack()
+        self.choice_stack: MutableSequence[Choice] = []
+        self.group_stack: MutableSequence[Group] = []
+        # index of the next choice of the current location
+        self.choice_count = 0
 
     def get_indent(self, mod = 0) -> str:
         return "  " * (self.thread_stack.len() + mod)
@@ -97,6 +234,8 @@ class RustTarget(BaseCompiler):
             f"{self.output_dir}/src/locations/{location.name}.rs", "a"
         )
 
+        self.choice_count = 0
+
         # create the main group
         self.thread_stack.add_group()
 
@@ -142,34 +281,95 @@ f"""
     ):
         for port_name, data in dataset:
             self.current_location.data[data.name] = data
-            if data.type == "file":
-                self.programs[self.current_location.name].write(f"""
-{self.get_indent()}swirl.init_port("{port_name}".into(), PortData::File("{data.value}".to_string())).await;"""
-                )
+            self.programs[self.current_location.name].write(f"""
+{self.get_indent()}swirl.init_port("{port_name}".into(), {port_data_literal(data.type, data.value)}).await;"""
+            )
 
-            elif data.type == "string":
-                self.programs[self.current_location.name].write(f"""
-{self.get_indent()}communicator.init_port("{port_name}".into(), PortData::String("{data.value}".to_string())).await;
-  """
-                )
+    def start_operation(self, guard: str = "Guard::Ready") -> None:
+        # the first operation of a branch of a choice is its guard
+        if self.choice_stack and not self.choice_stack[-1].started:
+            self.choice_stack[-1].started = True
+            self.choice_stack[-1].guard = guard
 
-            elif data.type == "int":
-                self.programs[self.current_location.name].write(f"""
-{self.get_indent()}communicator.init_port("{port_name}".into(), PortData::Int({data.value})).await;
-  """
-                )
+        if self.group_stack:
+            self.group_stack[-1].started = True
 
-            elif data.type == "bool":
-                self.programs[self.current_location.name].write(f"""
-{self.get_indent()}communicator.init_port("{port_name}".into(), PortData::Bool({data.value})).await;
-  """
-                )
+    def begin_branch(self) -> None:
+        # the branch is buffered, since the choice is written after its guards are known
+        self.programs[self.current_location.name] = io.StringIO()
+        self.thread_stack.add_group()
 
-            else:
-                raise ValueError(f"Unsupported data type: {data.type}")
+    def end_branch(self) -> None:
+        if ENABLE_BROADCAST: self.empty_broadcast_stack()
+
+        choice = self.choice_stack[-1]
+        program = self.programs[self.current_location.name]
+
+        # a branch made only of a choice is replaced by the branches of that choice
+        if (
+            choice.last is not None
+            and choice.last[0].starts_branch
+            and choice.last[1] == len(program.getvalue())
+        ):
+            choice.branches.extend(choice.last[0].branches)
+            self.thread_stack.pop_group()
+
+            # the index of the replaced choice is given to the next one
+            self.choice_count -= 1
+        else:
+            # wait for the remaining threads in the branch
+            self.wait_thread_group()
+            self.thread_stack.pop_group()
+
+            choice.branches.append((choice.guard, program.getvalue()))
+
+        choice.guard = "Guard::Ready"
+        choice.started = False
+        choice.last = None
+
+    def begin_choice(self) -> None:
+        if ENABLE_BROADCAST: self.empty_broadcast_stack()
+
+        starts_branch = bool(self.choice_stack) and not self.choice_stack[-1].started
+
+        self.start_operation()
+        self.refresh_join_set()
+
+        self.choice_stack.append(Choice(self.programs[self.current_location.name]))
+        self.choice_stack[-1].starts_branch = starts_branch
+        self.begin_branch()
 
     def choice(self):
-        raise NotImplementedError("Choice is not implemented yet")
+        self.end_branch()
+        self.begin_branch()
+
+    def end_choice(self) -> None:
+        choice = self.choice_stack[-1]
+
+        self.end_branch()
+        self.choice_stack.pop()
+        self.programs[self.current_location.name] = choice.program
+
+        branches = "".join(
+            f"""
+{self.get_indent(1)}Branch::new({guard}, {{ let swirl = swirl.clone(); async move {{
+{code}
+{self.get_indent(1)}}}}}),"""
+            for guard, code in choice.branches
+        )
+
+        choice.program.write(
+            f"""
+{self.get_indent()}//  ===================== choice start =====================
+{self.get_indent()}join_set = swirl.choice(join_set, {self.choice_count}, vec![{branches}
+{self.get_indent()}]).await;
+{self.get_indent()}//  ===================== choice end =====================
+""")
+        self.choice_count += 1
+        self.thread_stack.add_thread()
+
+        if self.choice_stack:
+            self.choice_stack[-1].last = (choice, len(choice.program.getvalue()))
 
     def exec(
         self,
@@ -177,24 +377,65 @@ f"""
         flow: tuple[set[tuple[str, str]], set[tuple[str, str]]],
         mapping: set[str],
     ):
-        program = self.programs[self.current_location.name]
+        # the condition of a group is the `when` of the step starting it
+        if step.when_scope == "group":
+            if not self.group_stack or self.group_stack[-1].started:
+                raise Exception(
+                    f"Step {step.name} has `whenScope: group` but does not start a parenthesized group"
+                )
+            self.group_stack[-1].condition = step.when
 
-        outputs = flow[1]
-        output_port_name = next(iter(outputs))[0] if outputs else ""
+        self.start_operation()
 
-        # output port
-        output_port = "None"
-        if output_port_name:
-            output_port = f"Some(\"{output_port_name}\".into())"
+        program = self.programs[self.current_location.name]
 
-        # output
-        output = "StepOutput::None"
-        if output_port_name:
-            output_value = f"\"{step.processors[output_port_name].glob}\""
-            output = f"StepOutput::File({output_value}.to_string())"
+        # outputs, one entry for each output port of the step
+        outputs = ", ".join(
+            [
+                f"(\"{port_name}\".into(), {step_output_literal(step.name, step.processors[port_name])})"
+                for port_name, _ in sorted(flow[1])
+            ]
+        )
 
         input_ports = ", ".join([f"\"{port_name}\".into()" for port_name, _ in flow[0]])
 
+        # step options, only the settings that differ from the defaults are written
+        step_options = []
+        if step.shell:
+            step_options.append("shell: true")
+        if step.env:
+            env = ", ".join(
+                f"({rust_string(name)}.into(), {rust_string(value)}.into())"
+                for name, value in sorted(step.env.items())
+            )
+            step_options.append(f"env: HashMap::from([{env}])")
+        if isinstance(step.stdin, Port):
+            step_options.append(f"stdin: Some(StepArgument::Port(\"{step.stdin.name}\".into()))")
+        elif step.stdin is not None:
+            step_options.append(f"stdin: Some(StepArgument::String({rust_string(step.stdin)}.into()))")
+        if step.timeout is not None:
+            step_options.append(
+                f"timeout: Some(std::time::Duration::from_secs_f64({float(step.timeout)!r}))"
+            )
+        if step.retry is not None:
+            step_options.append(f"retry: {retry_policy_literal(step.retry)}")
+        if step.cores is not None:
+            step_options.append(f"cores: {int(step.cores)}")
+        if step.memory is not None:
+            step_options.append(f"memory: {int(step.memory)}")
+        if step.limits:
+            step_options.append(f"limits: {resource_limits_literal(step.limits)}")
+        if step.accepted_exit_codes:
+            codes = ", ".join(str(int(code)) for code in step.accepted_exit_codes)
+            step_options.append(f"accepted_exit_codes: vec![{codes}]")
+        if step.on_failure != "abort":
+            step_options.append(f"on_failure: {FAILURE_POLICIES[step.on_failure]}")
+        if step.when is not None:
+            step_options.append(f"when: Some(\"{step.when}\".into())")
+        options = "StepOptions::default()"
+        if step_options:
+            options = f"StepOptions {{ {', '.join(step_options)}, ..Default::default() }}"
+
         # arguments
         arguments = ""
         for arg in step.arguments:
@@ -212,11 +453,11 @@ f"""
 {self.get_indent(1)}"{step.name}".to_string(), // name
 {self.get_indent(1)}"{step.display_name}".to_string(), // display name
 {self.get_indent(1)}vec![{input_ports}], // input ports
-{self.get_indent(1)}{output_port}, // output port
-{self.get_indent(1)}{output}, // output type
+{self.get_indent(1)}vec![{outputs}], // outputs
 {self.get_indent(1)}"{step.command}".to_string(), // command
 {self.get_indent(1)}vec![{arguments}
 {self.get_indent(1)}], // arguments
+{self.get_indent(1)}{options}, // options
 {self.get_indent()}).await;
 
 """
@@ -224,6 +465,8 @@ f"""
 
 
     def recv(self, port: str, data_type: str, src: str, dst: str):
+        self.start_operation(f"Guard::Receive {{ port: \"{port}\".into(), sender: \"{src}\".into() }}")
+
         program = self.programs[self.current_location.name]
         
         # assigns the receive to a new thread in the current group
@@ -235,6 +478,8 @@ f"""
         )
 
     def send(self, data: str, port: str, data_type: str, src: str, dst: str):
+        self.start_operation()
+
         program = self.programs[self.current_location.name]
 
         if ENABLE_BROADCAST:
@@ -285,36 +530,47 @@ f"""
         self.wait_thread_group()
 
         program.write(f"""
-{self.get_indent()}//  ===================== sequential step (follows) =====================""")
+{self.get_indent()}//  ===================== sequential step (follows) =====================
+{self.get_indent()}swirl.plan_sequential();""")
     
     def begin_paren(self) -> None:
         if ENABLE_BROADCAST: self.empty_broadcast_stack()
 
-        program = self.programs[self.current_location.name]
+        if self.group_stack:
+            self.group_stack[-1].started = True
 
         self.refresh_join_set()
 
-        program.write(f"""
-{self.get_indent()}//  ===================== group start =====================
-{self.get_indent()}join_set.spawn({{ let swirl = swirl.clone(); async move {{
-""")
+        # the group is buffered, since it is written after its first step is known
+        self.group_stack.append(Group(self.programs[self.current_location.name]))
+        self.programs[self.current_location.name] = io.StringIO()
+
         self.thread_stack.add_thread()
         self.thread_stack.add_group()
 
     def end_paren(self):
         if ENABLE_BROADCAST: self.empty_broadcast_stack()
 
-        program = self.programs[self.current_location.name]
-
         # wait for the remaining threads in the current group
         self.wait_thread_group()
 
         # remove the current group from the stack
         self.thread_stack.pop_group()
-        
-        program.write(
-            f"""
-{self.get_indent()}}}}});
+
+        group = self.group_stack.pop()
+        code = self.programs[self.current_location.name].getvalue()
+        self.programs[self.current_location.name] = group.program
+
+        if group.condition is None:
+            start = "join_set = swirl.group(join_set, "
+        else:
+            start = f"join_set = swirl.group_when(join_set, \"{group.condition}\".into(), "
+
+        group.program.write(f"""
+{self.get_indent()}//  ===================== group start =====================
+{self.get_indent()}{start}{{ let swirl = swirl.clone(); async move {{
+{code}
+{self.get_indent()}}}}}).await;
 {self.get_indent()}//  ===================== group end =====================
 """)
     
@@ -183,9 +183,20 @@ class CompileVisitor(SWIRLVisitor, ABC):
                     port_name: Processor(
                         self.metadata["dependencies"][value["dataName"]]["type"],
                         value.get("glob", None),
+                        value.get("stdout", False),
                     )
                     for port_name, value in outdata_patterns.items()
                 },
+                shell=step_metadata.get("shell", False),
+                timeout=step_metadata.get("timeout", None),
+                retry=step_metadata.get("retry", None),
+                accepted_exit_codes=step_metadata.get("acceptedExitCodes", None),
+                on_failure=step_metadata.get("onFailure", "abort"),
+                when=step_metadata.get("when", None),
+                when_scope=step_metadata.get("whenScope", "step"),
+                cores=step_metadata.get("cores", None),
+                memory=step_metadata.get("memory", None),
+                limits=step_metadata.get("limits", None),
             )
             self.workflow.add_step(step)
             for port_name, _ in inputs:
@@ -233,6 +244,39 @@ class CompileVisitor(SWIRLVisitor, ABC):
                 )
                 for arg in step_metadata["arguments"]
             ]
+            if step.when is None and "whenScope" in step_metadata:
+                raise Exception(
+                    f"Step {step.name} has the `whenScope` field without the `when` field in the metadata file"
+                )
+            if step.when is not None:
+                when_data = next(
+                    (data_name for port_name, data_name in inputs if port_name == step.when),
+                    None,
+                )
+                if when_data is None:
+                    raise Exception(
+                        f"Step {step.name} has invalid port name {step.when} in the `when` field in the metadata file"
+                    )
+                if self.metadata["dependencies"][when_data]["type"] != "bool":
+                    raise Exception(
+                        f"Step {step.name} has port {step.when} of type {self.metadata['dependencies'][when_data]['type']} in the `when` field in the metadata file, expected bool"
+                    )
+            if (stdin := step_metadata.get("stdin")) is not None:
+                if "valueFrom" in stdin and stdin["valueFrom"] not in (
+                    port_name for port_name, _ in inputs
+                ):
+                    raise Exception(
+                        f"Step {step.name} has invalid port name {stdin['valueFrom']} in the `stdin` field in the metadata file"
+                    )
+                step.stdin = (
+                    stdin["value"]
+                    if "value" in stdin
+                    else self.workflow.ports[stdin["valueFrom"]]
+                )
+            step.env = {
+                name: str(value).lower() if isinstance(value, bool) else str(value)
+                for name, value in step_metadata.get("env", {}).items()
+            }
         for loc in mapping:
             self.workflow.map(self.workflow.steps[name], self.workflow.locations[loc])
         return self.compiler.exec(self.workflow.steps[name], flow, mapping)
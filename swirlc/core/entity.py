@@ -93,11 +93,12 @@ class Port:
 
 
 class Processor:
-    __slots__ = ("type", "glob")
+    __slots__ = ("type", "glob", "stdout")
 
-    def __init__(self, type: str, glob: str | None):
+    def __init__(self, type: str, glob: str | None, stdout: bool = False):
         self.type: str = type
         self.glob: str | None = glob
+        self.stdout: bool = stdout
 
 
 class Step:
@@ -107,6 +108,18 @@ class Step:
         "command",
         "arguments",
         "processors",
+        "shell",
+        "env",
+        "stdin",
+        "timeout",
+        "retry",
+        "accepted_exit_codes",
+        "on_failure",
+        "when",
+        "when_scope",
+        "cores",
+        "memory",
+        "limits",
     )
 
     def __init__(
@@ -116,12 +129,36 @@ class Step:
         command: str | None = None,
         arguments: MutableSequence[str | Port] | None = None,
         processors: MutableMapping[str, Processor] | None = None,
+        shell: bool = False,
+        env: MutableMapping[str, str] | None = None,
+        stdin: str | Port | None = None,
+        timeout: float | None = None,
+        retry: MutableMapping[str, Any] | None = None,
+        accepted_exit_codes: MutableSequence[int] | None = None,
+        on_failure: str = "abort",
+        when: str | None = None,
+        when_scope: str = "step",
+        cores: int | None = None,
+        memory: int | None = None,
+        limits: MutableMapping[str, int] | None = None,
     ):
         self.name: str = name
         self.display_name: str = display_name
         self.command: str | None = command
         self.arguments: MutableSequence[str | Port] | None = arguments
         self.processors: MutableMapping[str, Processor] | None = processors
+        self.shell: bool = shell
+        self.env: MutableMapping[str, str] = env or {}
+        self.stdin: str | Port | None = stdin
+        self.timeout: float | None = timeout
+        self.retry: MutableMapping[str, Any] | None = retry
+        self.accepted_exit_codes: MutableSequence[int] = accepted_exit_codes or []
+        self.on_failure: str = on_failure
+        self.when: str | None = when
+        self.when_scope: str = when_scope
+        self.cores: int | None = cores
+        self.memory: int | None = memory
+        self.limits: MutableMapping[str, int] = limits or {}
 
 
 class Workflow:
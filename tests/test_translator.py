@@ -12,6 +12,7 @@ from swirlc.core.entity import (
     Data,
 )
 from swirlc.core.translator import AbstractTranslator
+from swirlc.main import main
 from tests.utils.utils import get_sha1
 
 
@@ -95,3 +96,285 @@ def test_translate():
         translator.translate(workflow_fd, metadata_fd)
         assert get_sha1(workflow_fd.name) == "da39a3ee5e6b4b0d3255bfef95601890afd80709"
         assert get_sha1(metadata_fd.name) == "d5ff5195ce296fcd334b6cbb4366b0b2b3e34c88"
+
+
+def compile_rust(tmp_path, workflow: str, metadata: str, location: str = "l1") -> str:
+    """Compile `workflow` to the Rust target, returning the code of `location`."""
+    workflow_path = tmp_path / "workflow.swirl"
+    workflow_path.write_text(workflow)
+    metadata_path = tmp_path / "config.yml"
+    metadata_path.write_text(metadata)
+
+    out = tmp_path / "out"
+    assert (
+        main(
+            [
+                "compile",
+                "--target",
+                "rust",
+                "--out",
+                str(out),
+                str(workflow_path),
+                str(metadata_path),
+            ]
+        )
+        == 0
+    )
+    return (out / "src" / "locations" / f"{location}.rs").read_text()
+
+
+def test_rust_receive_twice(tmp_path):
+    """Test that each value sent through a port is received before the step using it."""
+    code = compile_rust(
+        tmp_path,
+        "<ld, {}, exec(s1,{}->{(p1,d1)},{ld}).send(d1->p1,ld,l1)"
+        ".exec(s2,{(p1,d1)}->{(p1,d1)},{ld}).send(d1->p1,ld,l1)> |\n"
+        "<l1, {}, recv(p1,ld,l1).exec(s3,{(p1,d1)}->{},{l1})"
+        ".recv(p1,ld,l1).exec(s4,{(p1,d1)}->{},{l1})>\n",
+        """version: v1.0
+steps:
+  s1:
+    displayName: Produce
+    command: echo
+    arguments:
+      - value: "1"
+    outputs:
+      p1:
+        dataName: d1
+        stdout: true
+  s2:
+    displayName: Increment
+    command: expr
+    arguments:
+      - valueFrom: p1
+      - value: "+"
+      - value: "1"
+    outputs:
+      p1:
+        dataName: d1
+        stdout: true
+  s3:
+    displayName: First
+    command: echo
+    arguments:
+      - valueFrom: p1
+  s4:
+    displayName: Second
+    command: echo
+    arguments:
+      - valueFrom: p1
+locations:
+  ld:
+    hostname: 127.0.0.1
+    port: 8080
+  l1:
+    hostname: 127.0.0.1
+    port: 8081
+dependencies:
+  d1:
+    type: int
+""",
+    )
+
+    # each step waits for the receive preceding it, which empties the port
+    receive = 'swirl.receive("p1".into(), "ld".into(), join_set).await;'
+    first_receive = code.index(receive)
+    second_receive = code.index(receive, first_receive + 1)
+    first_wait = code.index("join_set.join_all().await;", first_receive)
+    second_wait = code.index("join_set.join_all().await;", second_receive)
+    assert (
+        first_receive < first_wait < code.index('"s3".to_string()') < second_receive
+    )
+    assert second_receive < second_wait < code.index('"s4".to_string()')
+
+
+CHOICE_METADATA = """version: v1.0
+steps:
+  s1:
+    displayName: A
+    command: echo
+    arguments:
+      - value: a
+  s2:
+    displayName: B
+    command: echo
+    arguments:
+      - value: b
+  s3:
+    displayName: C
+    command: echo
+    arguments:
+      - value: c
+  s4:
+    displayName: D
+    command: echo
+    arguments:
+      - value: d
+locations:
+  l1:
+    hostname: 127.0.0.1
+    port: 8080
+dependencies: {}
+"""
+
+
+def test_rust_choice(tmp_path):
+    """Test that a choice is compiled to a runtime choice between its branches."""
+    code = compile_rust(
+        tmp_path,
+        "<l1, {}, exec(s1,{}->{},{l1}) + exec(s2,{}->{},{l1})>\n",
+        CHOICE_METADATA,
+    )
+
+    assert code.count("swirl.choice(") == 1
+    assert code.count("Branch::new(Guard::Ready") == 2
+    assert (
+        code.index("swirl.choice(join_set, 0, vec![")
+        < code.index('"s1".to_string()')
+        < code.index('"s2".to_string()')
+    )
+
+
+def test_rust_choice_three_way(tmp_path):
+    """Test that the left-nested choices of `a + b + c` are compiled to one choice."""
+    code = compile_rust(
+        tmp_path,
+        "<l1, {}, exec(s1,{}->{},{l1}) + exec(s2,{}->{},{l1})"
+        " + exec(s3,{}->{},{l1})>\n",
+        CHOICE_METADATA,
+    )
+
+    assert code.count("swirl.choice(") == 1
+    assert code.count("Branch::new(Guard::Ready") == 3
+    assert (
+        code.index("swirl.choice(join_set, 0, vec![")
+        < code.index('"s1".to_string()')
+        < code.index('"s2".to_string()')
+        < code.index('"s3".to_string()')
+    )
+
+
+def test_rust_choice_then_seq(tmp_path):
+    """Test that a choice followed by a step is kept as a branch of the outer choice."""
+    # `a + b . c + d` is the choice between `(a + b) . c` and `d`
+    code = compile_rust(
+        tmp_path,
+        "<l1, {}, exec(s1,{}->{},{l1}) + exec(s2,{}->{},{l1})"
+        " . exec(s3,{}->{},{l1}) + exec(s4,{}->{},{l1})>\n",
+        CHOICE_METADATA,
+    )
+
+    assert code.count("swirl.choice(") == 2
+    assert code.count("Branch::new(Guard::Ready") == 4
+
+    outer = code.index("swirl.choice(join_set, 1, vec![")
+    inner = code.index("swirl.choice(join_set, 0, vec![")
+    inner_end = code.index("]).await;", inner)
+    last_branch = code.rindex("Branch::new(Guard::Ready")
+    assert (
+        outer
+        < inner
+        < code.index('"s1".to_string()')
+        < code.index('"s2".to_string()')
+    )
+    assert inner_end < code.index('"s3".to_string()') < last_branch
+    assert last_branch < code.index('"s4".to_string()')
+
+
+GROUP_WHEN_METADATA = """version: v1.0
+steps:
+  s0:
+    displayName: Decide
+    command: echo
+    arguments:
+      - value: "false"
+    outputs:
+      pc:
+        dataName: dc
+        stdout: true
+  s1:
+    displayName: Produce
+    command: echo
+    when: pc
+    whenScope: group
+    arguments:
+      - value: hello
+    outputs:
+      p1:
+        dataName: d1
+        stdout: true
+  s2:
+    displayName: Consume
+    command: echo
+    arguments:
+      - valueFrom: p1
+  s3:
+    displayName: Other
+    command: echo
+    arguments:
+      - value: other
+locations:
+  ld:
+    hostname: 127.0.0.1
+    port: 8080
+  l1:
+    hostname: 127.0.0.1
+    port: 8081
+dependencies:
+  dc:
+    type: bool
+  d1:
+    type: string
+"""
+
+
+def test_rust_group_when(tmp_path):
+    """Test that a group started by a step with `whenScope: group` runs on its port."""
+    code = compile_rust(
+        tmp_path,
+        "<ld, {}, exec(s0,{}->{(pc,dc)},{ld}) . (exec(s1,{(pc,dc)}->{(p1,d1)},{ld})"
+        " . exec(s3,{}->{},{ld}) . send(d1->p1,ld,l1))> |\n"
+        "<l1, {}, recv(p1,ld,l1).exec(s2,{(p1,d1)}->{},{l1})>\n",
+        GROUP_WHEN_METADATA,
+        location="ld",
+    )
+
+    # the steps and the sends of the group are skipped with it
+    group = code.index('swirl.group_when(join_set, "pc".into(), ')
+    group_end = code.index("group end", group)
+    assert code.index('"s0".to_string()') < group
+    assert (
+        group
+        < code.index('"s1".to_string()')
+        < code.index('"s3".to_string()')
+        < code.index('swirl.send("p1".into(), "l1".into(), join_set)')
+        < group_end
+    )
+    assert "swirl.group(join_set" not in code
+
+
+def test_rust_group_when_not_first(tmp_path):
+    """Test that a step with `whenScope: group` must start a parenthesized group."""
+    workflow_path = tmp_path / "workflow.swirl"
+    workflow_path.write_text(
+        "<ld, {}, exec(s0,{}->{(pc,dc)},{ld}) . (exec(s3,{}->{},{ld})"
+        " . exec(s1,{(pc,dc)}->{(p1,d1)},{ld}) . send(d1->p1,ld,l1))> |\n"
+        "<l1, {}, recv(p1,ld,l1).exec(s2,{(p1,d1)}->{},{l1})>\n"
+    )
+    metadata_path = tmp_path / "config.yml"
+    metadata_path.write_text(GROUP_WHEN_METADATA)
+
+    assert (
+        main(
+            [
+                "compile",
+                "--target",
+                "rust",
+                "--out",
+                str(tmp_path / "out"),
+                str(workflow_path),
+                str(metadata_path),
+            ]
+        )
+        == 1
+    )